@@ -1,11 +1,192 @@
+use crate::gameplay::{DEFAULT_TICK_RATE, MatchSettings};
 use bevy::prelude::*;
 use clap::Parser;
+use url::Url;
 
 #[derive(Parser, Resource, Debug, Clone)]
 pub struct Args {
     /// runs the game in synctest mode
     #[clap(long)]
     pub synctest: bool,
-    #[clap(long, default_value = "2")]
-    pub input_delay: usize,
+    /// how many frames synctest rolls back every frame
+    #[clap(long, default_value = "2", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(0..=7))]
+    pub check_distance: usize,
+    /// where synctest gets inputs from: "keyboard", "random", or the path of a replay file
+    #[clap(long, default_value = "keyboard", value_parser = parse_input_source)]
+    pub synctest_input: InputSource,
+    /// frames of input delay, chosen from the ping to the other players by
+    /// default. Overrides measured values of other players too
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(0..=8))]
+    pub input_delay: Option<usize>,
+    /// url of the matchbox signaling server
+    #[clap(long, default_value = "ws://127.0.0.1:3536")]
+    pub matchbox: String,
+    /// code of a room to join right away, instead of creating or joining one in the lobby
+    #[clap(long)]
+    pub room: Option<String>,
+    /// number of players in the match, 2 to 8
+    #[clap(long, default_value = "2", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(2..=8))]
+    pub players: usize,
+    /// number of spectators to wait for before starting the match. More can
+    /// join with `--spectate` once it has started
+    #[clap(long, default_value = "0")]
+    pub spectators: usize,
+    /// joins the match as a spectator instead of a player, also after it has
+    /// started
+    #[clap(long)]
+    pub spectate: bool,
+    /// plays a match against other players on the same computer, sharing the
+    /// keyboard or using gamepads
+    #[clap(long)]
+    pub local: bool,
+    /// lets players get hit by their own bullets
+    #[clap(long)]
+    pub self_damage: bool,
+    /// makes bullets bounce off walls, a few times depending on the weapon
+    #[clap(long)]
+    pub ricochet: bool,
+    /// how many health points players start each round with. Most weapons take
+    /// away one per hit
+    #[clap(long, default_value = "3", value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(1..=255))]
+    pub max_health: u32,
+    /// rollback frames per second. Weapons and movement take as long in
+    /// seconds at any rate, higher rates just respond quicker but roll back
+    /// more frames
+    #[clap(long, default_value_t = DEFAULT_TICK_RATE, value_parser = clap::builder::RangedU64ValueParser::<u32>::new().range(10..=240))]
+    pub tick_rate: u32,
+    /// records the match to a replay file, downloaded when the match ends on wasm
+    #[clap(long)]
+    pub record: Option<String>,
+    /// plays back a replay file instead of starting a match
+    #[clap(long)]
+    pub replay: Option<String>,
+    /// simulates the game without a window, using random inputs, and checks
+    /// that rolling back doesn't change the outcome
+    #[clap(long)]
+    pub headless: bool,
+    /// exits after simulating this many frames in synctest and headless mode,
+    /// with an error if any frame changed when re-simulated. Defaults to 3600
+    /// in headless mode
+    #[clap(long)]
+    pub frames: Option<i32>,
+    /// seed for the map and random inputs in synctest and headless mode
+    #[clap(long)]
+    pub seed: Option<u64>,
+    /// prints the differences between two desync dumps, instead of starting the game
+    #[clap(long, num_args = 2, value_names = ["DUMP_A", "DUMP_B"])]
+    pub diff_desync: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputSource {
+    Keyboard,
+    /// Seeded random inputs, see `--seed`
+    Random,
+    /// Inputs of a recorded replay
+    File(String),
+}
+
+fn parse_input_source(value: &str) -> Result<InputSource, String> {
+    Ok(match value {
+        "keyboard" => InputSource::Keyboard,
+        "random" => InputSource::Random,
+        path => InputSource::File(path.to_string()),
+    })
+}
+
+impl Args {
+    /// Reads args from the command line on native, and from the page's query
+    /// string on wasm, i.e. `?room=foo&players=3` becomes `--room=foo --players=3`
+    pub fn get() -> Self {
+        #[cfg(target_arch = "wasm32")]
+        {
+            Self::from_query_string()
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            Self::parse()
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn from_query_string() -> Self {
+        let href = web_sys::window()
+            .and_then(|window| window.location().href().ok())
+            .unwrap_or_default();
+
+        let args: Vec<String> = Url::parse(&href)
+            .map(|url| {
+                url.query_pairs()
+                    .map(|(key, value)| {
+                        if value.is_empty() {
+                            // flags, like `?synctest`
+                            format!("--{key}")
+                        } else {
+                            format!("--{key}={value}")
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // clap expects the first arg to be the binary name
+        let args = std::iter::once("extreme_bevy".to_string()).chain(args);
+
+        Self::try_parse_from(args).unwrap_or_else(|err| {
+            error!("failed to parse query string, using defaults: {err}");
+            Self::parse_from(["extreme_bevy"])
+        })
+    }
+
+    /// Where inputs are read from in the current mode
+    pub fn input_source(&self) -> InputSource {
+        if let Some(path) = &self.replay {
+            InputSource::File(path.clone())
+        } else if self.synctest {
+            self.synctest_input.clone()
+        } else {
+            InputSource::Keyboard
+        }
+    }
+
+    /// Rules of the match, every peer needs to have the same ones
+    pub fn match_settings(&self) -> MatchSettings {
+        MatchSettings {
+            self_damage: self.self_damage,
+            max_health: self.max_health,
+            ricochet: self.ricochet,
+            tick_rate: self.tick_rate,
+        }
+    }
+
+    /// Number of peers in the match, both players and spectators
+    pub fn num_peers(&self) -> usize {
+        self.players + self.spectators
+    }
+
+    /// The full url of a matchbox room, e.g. `ws://127.0.0.1:3536/ABCDE`. All
+    /// peers with the same room code end up in the same room, so spectators
+    /// can still join after the match has started
+    pub fn room_url(&self, room: &str) -> Result<Url, String> {
+        let mut url = Url::parse(&self.matchbox)
+            .map_err(|err| format!("invalid matchbox url {:?}: {err}", self.matchbox))?;
+
+        if !matches!(url.scheme(), "ws" | "wss") {
+            return Err(format!(
+                "matchbox url must start with ws:// or wss://, got {:?}",
+                self.matchbox
+            ));
+        }
+
+        if room.is_empty() || room.contains(['/', '?', '#', '&']) {
+            return Err(format!("invalid room code {room:?}"));
+        }
+
+        url.path_segments_mut()
+            .map_err(|_| format!("matchbox url can't have a path: {:?}", self.matchbox))?
+            .pop_if_empty()
+            .push(room);
+
+        Ok(url)
+    }
 }
@@ -0,0 +1,248 @@
+use crate::{
+    input::Action,
+    storage::{load_config, save_config},
+};
+use bevy::{
+    platform::collections::HashMap,
+    prelude::*,
+    reflect::{DynamicEnum, DynamicVariant, Enum, FromReflect},
+};
+use bevy_egui::{
+    EguiContexts,
+    egui::{self, Align2},
+};
+
+const BINDINGS_CONFIG: &str = "key_bindings.txt";
+
+/// How many local players can share a keyboard
+pub const KEYBOARD_SLOTS: usize = 2;
+
+/// Keys bound to each action, for each player sharing the keyboard. A single
+/// local player can use the keys of every slot.
+#[derive(Resource, Debug, Clone)]
+pub struct KeyBindings([HashMap<Action, Vec<KeyCode>>; KEYBOARD_SLOTS]);
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self([
+            HashMap::from_iter([
+                (Action::Up, vec![KeyCode::KeyW]),
+                (Action::Down, vec![KeyCode::KeyS]),
+                (Action::Left, vec![KeyCode::KeyA]),
+                (Action::Right, vec![KeyCode::KeyD]),
+                (Action::Fire, vec![KeyCode::Space]),
+                (Action::Reload, vec![KeyCode::KeyR]),
+                (Action::Aim, vec![KeyCode::ShiftLeft]),
+            ]),
+            HashMap::from_iter([
+                (Action::Up, vec![KeyCode::ArrowUp]),
+                (Action::Down, vec![KeyCode::ArrowDown]),
+                (Action::Left, vec![KeyCode::ArrowLeft]),
+                (Action::Right, vec![KeyCode::ArrowRight]),
+                (Action::Fire, vec![KeyCode::Enter]),
+                (Action::Reload, vec![KeyCode::ControlRight]),
+                (Action::Aim, vec![KeyCode::ShiftRight]),
+            ]),
+        ])
+    }
+}
+
+impl KeyBindings {
+    /// Loads saved bindings, from a file on native, or localStorage on wasm
+    pub fn load() -> Self {
+        let Some(text) = load_config(BINDINGS_CONFIG) else {
+            return default();
+        };
+
+        Self::decode(&text).unwrap_or_else(|err| {
+            warn!("invalid key bindings, using defaults: {err}");
+            default()
+        })
+    }
+
+    fn save(&self) {
+        if let Err(err) = save_config(BINDINGS_CONFIG, &self.encode()) {
+            error!("failed to save key bindings: {err}");
+        }
+    }
+
+    /// Empty for slots past [`KEYBOARD_SLOTS`], those players need a gamepad
+    pub fn keys(&self, slot: usize, action: Action) -> &[KeyCode] {
+        self.0
+            .get(slot)
+            .and_then(|actions| actions.get(&action))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Binds `key` to `action`, and unbinds it from any other action or slot
+    fn bind(&mut self, slot: usize, action: Action, key: KeyCode) {
+        for keys in self.0.iter_mut().flat_map(|actions| actions.values_mut()) {
+            keys.retain(|bound| *bound != key);
+        }
+        self.0[slot].entry(action).or_default().push(key);
+    }
+
+    /// One line per slot and action, e.g. `1.up=KeyW,KeyI`
+    fn encode(&self) -> String {
+        (0..KEYBOARD_SLOTS)
+            .flat_map(|slot| {
+                Action::ALL.iter().map(move |action| {
+                    let keys = self.key_names(slot, *action).join(",");
+                    format!("{}.{}={keys}", slot + 1, action.name())
+                })
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Actions missing from `text` keep their default bindings
+    fn decode(text: &str) -> Result<Self, String> {
+        let mut bindings = Self::default();
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let (slot_and_name, keys) = line
+                .split_once('=')
+                .ok_or_else(|| format!("invalid line {line:?}"))?;
+            let (slot, name) = slot_and_name
+                .split_once('.')
+                .ok_or_else(|| format!("invalid line {line:?}"))?;
+            let slot = slot
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|slot| (1..=KEYBOARD_SLOTS).contains(slot))
+                .ok_or_else(|| format!("invalid keyboard slot {slot:?}"))?
+                - 1;
+            let action = Action::ALL
+                .into_iter()
+                .find(|action| action.name() == name.trim())
+                .ok_or_else(|| format!("unknown action {name:?}"))?;
+            let keys = keys
+                .split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(parse_key_code)
+                .collect::<Result<_, _>>()?;
+            bindings.0[slot].insert(action, keys);
+        }
+
+        Ok(bindings)
+    }
+
+    fn key_names(&self, slot: usize, action: Action) -> Vec<&str> {
+        self.keys(slot, action)
+            .iter()
+            .map(|key| key.variant_name())
+            .collect()
+    }
+}
+
+/// Keys are stored by variant name, e.g. `KeyW`, and turned back into a
+/// `KeyCode` through reflection
+fn parse_key_code(name: &str) -> Result<KeyCode, String> {
+    KeyCode::from_reflect(&DynamicEnum::new(name.to_string(), DynamicVariant::Unit))
+        .ok_or_else(|| format!("unknown key {name:?}"))
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct BindingsUi {
+    pub open: bool,
+    /// Keyboard slot and action waiting for a key press to bind
+    listening: Option<(usize, Action)>,
+}
+
+pub fn bindings_ui_open(state: Res<BindingsUi>) -> bool {
+    state.open
+}
+
+pub fn toggle_bindings_ui(keys: Res<ButtonInput<KeyCode>>, mut state: ResMut<BindingsUi>) {
+    if keys.just_pressed(KeyCode::F1) {
+        state.open = !state.open;
+        state.listening = None;
+    }
+}
+
+pub fn bindings_ui(
+    mut contexts: EguiContexts,
+    mut state: ResMut<BindingsUi>,
+    mut bindings: ResMut<KeyBindings>,
+    keys: Res<ButtonInput<KeyCode>>,
+) -> Result {
+    if let Some((slot, action)) = state.listening
+        && let Some(key) = keys.get_just_pressed().next()
+    {
+        if *key != KeyCode::Escape {
+            bindings.bind(slot, action, *key);
+            bindings.save();
+        }
+        state.listening = None;
+    }
+
+    let mut changed = false;
+
+    egui::Window::new("Key bindings")
+        .anchor(Align2::RIGHT_TOP, (-25., 25.))
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.label("Players sharing the keyboard each have their own keys");
+
+            egui::Grid::new("key_bindings")
+                .striped(true)
+                .show(ui, |ui| {
+                    ui.label("");
+                    for slot in 0..KEYBOARD_SLOTS {
+                        ui.strong(format!("Player {}", slot + 1));
+                        ui.label("");
+                        ui.label("");
+                    }
+                    ui.end_row();
+
+                    for action in Action::ALL {
+                        ui.strong(action.name());
+
+                        for slot in 0..KEYBOARD_SLOTS {
+                            let keys = bindings.key_names(slot, action).join(", ");
+                            let keys = if keys.is_empty() {
+                                "unbound"
+                            } else {
+                                keys.as_str()
+                            };
+                            ui.label(keys);
+
+                            if state.listening == Some((slot, action)) {
+                                ui.label("Press a key (Esc cancels)");
+                            } else if ui.button("Add key").clicked() {
+                                state.listening = Some((slot, action));
+                            }
+
+                            if ui.button("Clear").clicked() {
+                                bindings.0[slot].remove(&action);
+                                changed = true;
+                            }
+                        }
+                        ui.end_row();
+                    }
+                });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.button("Reset to defaults").clicked() {
+                    *bindings = default();
+                    changed = true;
+                }
+                if ui.button("Close").clicked() {
+                    state.open = false;
+                    state.listening = None;
+                }
+            });
+        });
+
+    if changed {
+        bindings.save();
+    }
+
+    Ok(())
+}
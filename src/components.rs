@@ -1,65 +1,117 @@
+use crate::{fixed, weapons::Weapon};
 use bevy::prelude::*;
-use bevy_ggrs::checksum_hasher;
-use std::{
-    f32::consts::PI,
-    hash::{Hash, Hasher},
-};
 
-#[derive(Component, Clone, Copy)]
+/// Where an entity is in the game, in `fixed::UNIT`s. `Transform` follows it,
+/// but only for rendering.
+#[derive(Component, Clone, Copy, Debug)]
+#[require(Transform)]
+pub struct Position(pub IVec2);
+
+#[derive(Component, Clone, Copy, Debug)]
 #[require(DistanceTraveled)]
 pub struct Player {
     pub handle: usize,
 }
 
-#[derive(Component, Clone, Copy)]
-pub struct BulletReady(pub bool);
+/// What's left in a player's magazine, and what the gun is waiting for before
+/// it can fire again
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Ammo {
+    pub rounds: u32,
+    pub magazine_size: u32,
+    /// Frames until the magazine is full again, 0 when not reloading
+    pub reload_frames: u32,
+    /// Frames until the next shot, limits how fast players can tap fire
+    pub cooldown_frames: u32,
+    /// Fire has been released since the last shot
+    pub trigger_released: bool,
+}
 
-#[derive(Component, Clone, Copy)]
-pub struct Bullet;
+impl Ammo {
+    pub fn new(magazine_size: u32) -> Self {
+        Self {
+            rounds: magazine_size,
+            magazine_size,
+            reload_frames: 0,
+            cooldown_frames: 0,
+            trigger_released: true,
+        }
+    }
 
-#[derive(Component, Clone, Copy)]
-pub struct MoveDir(pub Vec2);
+    pub fn reloading(&self) -> bool {
+        self.reload_frames > 0
+    }
 
-impl MoveDir {
-    /// Gets the index of the octant (45 degree sectors), starting from 0 (right) and going counter-clockwise:
-    pub fn octant(&self) -> usize {
-        // in radians, signed: 0 is right, PI/2 is up, -PI/2 is down
-        let angle = self.0.to_angle();
+    pub fn can_fire(&self) -> bool {
+        self.rounds > 0 && !self.reloading() && self.cooldown_frames == 0 && self.trigger_released
+    }
+}
 
-        // divide the angle by 45 degrees (PI/4) to get the octant
-        let octant = (angle / (PI / 4.)).round() as i32;
+#[derive(Component, Clone, Copy, Debug)]
+#[require(PreviousPosition)]
+pub struct Bullet {
+    /// Handle of the player who fired the bullet
+    pub owner: usize,
+    pub weapon: Weapon,
+    /// Frames until the bullet disappears
+    pub frames_left: u32,
+    /// Walls the bullet can still bounce off with `--ricochet`
+    pub bounces_left: u32,
+}
 
-        // convert to an octant index in the range [0, 7]
-        let octant = if octant < 0 { octant + 8 } else { octant } as usize;
+/// Swaps the weapon of the first player to walk over it
+#[derive(Component, Clone, Copy, Debug)]
+pub struct WeaponPickup {
+    pub weapon: Weapon,
+}
 
-        octant
-    }
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Health {
+    pub current: u32,
+    pub max: u32,
+    /// Frames left until the player can be hit again
+    pub invulnerable_frames: u32,
 }
 
-#[derive(Component, Default, Clone, Copy)]
-pub struct DistanceTraveled(pub f32);
+impl Health {
+    pub fn new(max: u32) -> Self {
+        Self {
+            current: max,
+            max,
+            invulnerable_frames: 0,
+        }
+    }
 
-#[derive(Component, Clone, Copy)]
-pub struct Wall;
+    pub fn fraction(&self) -> f32 {
+        self.current as f32 / self.max as f32
+    }
+}
 
-pub fn checksum_transform(transform: &Transform) -> u64 {
-    let mut hasher = checksum_hasher();
+/// A direction `fixed::UNIT` long
+#[derive(Component, Clone, Copy, Debug)]
+pub struct MoveDir(pub IVec2);
 
-    assert!(
-        transform.is_finite(),
-        "Hashing is not stable for NaN f32 values."
-    );
+/// Where the player is aiming, can differ from where they're moving
+#[derive(Component, Clone, Copy, Debug)]
+pub struct AimDir(pub IVec2);
 
-    transform.translation.x.to_bits().hash(&mut hasher);
-    transform.translation.y.to_bits().hash(&mut hasher);
-    transform.translation.z.to_bits().hash(&mut hasher);
+impl AimDir {
+    pub fn octant(&self) -> usize {
+        fixed::octant(self.0)
+    }
+}
 
-    transform.rotation.x.to_bits().hash(&mut hasher);
-    transform.rotation.y.to_bits().hash(&mut hasher);
-    transform.rotation.z.to_bits().hash(&mut hasher);
-    transform.rotation.w.to_bits().hash(&mut hasher);
+/// In `fixed::UNIT`s
+#[derive(Component, Default, Clone, Copy, Debug)]
+pub struct DistanceTraveled(pub i32);
 
-    // skip transform.scale as it's not used for gameplay
+/// Where a bullet was before it moved this frame. Collisions are checked along
+/// the way from there, so fast bullets can't skip past walls and players.
+#[derive(Component, Default, Clone, Copy, Debug)]
+pub struct PreviousPosition(pub IVec2);
 
-    hasher.finish()
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Wall {
+    /// Size in map cells
+    pub size: IVec2,
 }
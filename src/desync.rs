@@ -0,0 +1,216 @@
+use crate::{
+    components::*,
+    gameplay::{FrameCount, MatchStats, RollbackState, RoundEndTimer, Scores},
+    rollback::checksum,
+    storage::save_file,
+    weapons::Weapon,
+};
+use bevy::prelude::*;
+use std::collections::{BTreeMap, VecDeque};
+
+/// How many frames of state we keep around. Desyncs are detected once both
+/// peers have confirmed a frame, so this needs to cover the prediction window
+/// with some margin.
+const HISTORY_LEN: usize = 128;
+
+/// Text snapshots of the rollback state for the most recent frames.
+///
+/// Each line is `<entity or "resource">\t<component>\t<value>`, with values
+/// printed with `{:?}`. Gameplay state is all integers, so that's exact.
+#[derive(Resource, Default)]
+pub struct DesyncHistory {
+    snapshots: VecDeque<(i32, String)>,
+    /// Once peers desync, every frame after that desyncs as well, so only the
+    /// first desync of a session is dumped
+    dumped: bool,
+}
+
+impl DesyncHistory {
+    pub fn get(&self, frame: i32) -> Option<&str> {
+        self.snapshots
+            .iter()
+            .find(|(f, _)| *f == frame)
+            .map(|(_, snapshot)| snapshot.as_str())
+    }
+}
+
+pub fn record_snapshot(
+    frame: Res<FrameCount>,
+    mut history: ResMut<DesyncHistory>,
+    players: Query<(
+        &Player,
+        &Position,
+        &MoveDir,
+        &AimDir,
+        &Weapon,
+        &Ammo,
+        &Health,
+        &DistanceTraveled,
+    )>,
+    bullets: Query<(&Bullet, &Position, &MoveDir, &PreviousPosition)>,
+    pickups: Query<(&WeaponPickup, &Position)>,
+    scores: Res<Scores>,
+    stats: Res<MatchStats>,
+    round_end_timer: Res<RoundEndTimer>,
+    state: Res<State<RollbackState>>,
+) {
+    let mut lines = vec![
+        format!("resource\tFrameCount\t{:?}", frame.0),
+        format!("resource\tScores\t{:?}", scores.0),
+        format!("resource\tMatchStats\t{:?}", stats.0),
+        format!("resource\tRoundEndTimer\t{:?}", round_end_timer.0),
+        format!("resource\tRollbackState\t{:?}", state.get()),
+    ];
+
+    // entity ids differ between peers, so we label entities by what they are instead
+    let mut players: Vec<_> = players.iter().collect();
+    players.sort_by_key(|(player, ..)| player.handle);
+    for (player, position, move_dir, aim_dir, weapon, ammo, health, distance) in players {
+        let label = format!("player {}", player.handle);
+        lines.push(format!("{label}\tPosition\t{:?}", position.0));
+        lines.push(format!("{label}\tMoveDir\t{:?}", move_dir.0));
+        lines.push(format!("{label}\tAimDir\t{:?}", aim_dir.0));
+        lines.push(format!("{label}\tWeapon\t{weapon:?}"));
+        lines.push(format!("{label}\tAmmo\t{ammo:?}"));
+        lines.push(format!("{label}\tHealth\t{health:?}"));
+        lines.push(format!("{label}\tDistanceTraveled\t{:?}", distance.0));
+    }
+
+    // bullets don't have a stable identity, so sort them by owner and position
+    let mut bullets: Vec<_> = bullets.iter().collect();
+    bullets.sort_by_key(|(bullet, position, ..)| (bullet.owner, position.0.x, position.0.y));
+    for (i, (bullet, position, move_dir, previous)) in bullets.into_iter().enumerate() {
+        let label = format!("bullet {}.{i}", bullet.owner);
+        lines.push(format!("{label}\tBullet\t{bullet:?}"));
+        lines.push(format!("{label}\tPosition\t{:?}", position.0));
+        lines.push(format!("{label}\tMoveDir\t{:?}", move_dir.0));
+        lines.push(format!("{label}\tPreviousPosition\t{:?}", previous.0));
+    }
+
+    // pickups never move, so their position identifies them
+    let mut pickups: Vec<_> = pickups.iter().collect();
+    pickups.sort_by_key(|(_, position)| (position.0.x, position.0.y));
+    for (i, (pickup, position)) in pickups.into_iter().enumerate() {
+        let label = format!("pickup {i}");
+        lines.push(format!("{label}\tPosition\t{:?}", position.0));
+        lines.push(format!("{label}\tWeaponPickup\t{:?}", pickup.weapon));
+    }
+
+    // frames are re-simulated on rollback, so replace any older snapshot of this frame
+    let frame = frame.0;
+    history.snapshots.retain(|(f, _)| *f < frame);
+    history.snapshots.push_back((frame, lines.join("\n")));
+    while history.snapshots.len() > HISTORY_LEN {
+        history.snapshots.pop_front();
+    }
+}
+
+/// Checksums of every frame's snapshot. In a synctest, re-simulating a frame
+/// must give the same result as the first time, or rollback is broken.
+#[derive(Resource, Default)]
+pub struct FrameChecksums {
+    /// Starting with frame 1
+    pub checksums: Vec<u64>,
+    pub mismatched_frames: Vec<i32>,
+}
+
+pub fn record_checksum(
+    frame: Res<FrameCount>,
+    history: Res<DesyncHistory>,
+    mut checksums: ResMut<FrameChecksums>,
+) {
+    let snapshot = history
+        .get(frame.0)
+        .expect("snapshot of the current frame wasn't recorded");
+    let checksum = checksum().bytes(snapshot.as_bytes()).finish();
+
+    let i = frame.0 as usize - 1;
+    match checksums.checksums.get(i).copied() {
+        Some(previous) if previous != checksum => {
+            error!(
+                "Frame {} changed when re-simulated: {previous:X} != {checksum:X}",
+                frame.0
+            );
+            checksums.checksums[i] = checksum;
+            checksums.mismatched_frames.push(frame.0);
+        }
+        Some(_) => {}
+        None => checksums.checksums.push(checksum),
+    }
+}
+
+/// Saves the snapshot of the first desynced frame, to a file on native, or as
+/// a browser download on wasm
+pub fn dump_desync(
+    history: &mut DesyncHistory,
+    frame: i32,
+    local_checksum: u128,
+    remote_checksum: u128,
+) {
+    if history.dumped {
+        return;
+    }
+    history.dumped = true;
+
+    let Some(snapshot) = history.get(frame) else {
+        error!("no snapshot of frame {frame}, can't dump desync");
+        return;
+    };
+
+    let contents = format!(
+        "frame\t{frame}\nlocal_checksum\t{local_checksum:X}\nremote_checksum\t{remote_checksum:X}\n{snapshot}\n"
+    );
+    let file_name = format!("desync-{frame}-{local_checksum:X}.txt");
+
+    match save_file(&file_name, contents.as_bytes()) {
+        Ok(()) => info!("wrote desync dump to {file_name}"),
+        Err(err) => error!("failed to write desync dump {file_name}: {err}"),
+    }
+}
+
+/// Prints every component that differs between two desync dumps
+pub fn print_diff(path_a: &str, path_b: &str) -> Result<(), String> {
+    let read = |path: &str| {
+        std::fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))
+    };
+    let a = read(path_a)?;
+    let b = read(path_b)?;
+
+    let parse = |dump: &str| -> BTreeMap<(String, String), String> {
+        dump.lines()
+            .filter_map(|line| {
+                // skips the header lines, which only have two columns
+                let mut parts = line.splitn(3, '\t');
+                let entity = parts.next()?;
+                let component = parts.next()?;
+                let value = parts.next()?;
+                Some((
+                    (entity.to_string(), component.to_string()),
+                    value.to_string(),
+                ))
+            })
+            .collect()
+    };
+    let a = parse(&a);
+    let b = parse(&b);
+
+    let mut keys: Vec<_> = a.keys().chain(b.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut differences = 0;
+    for key in keys {
+        let (entity, component) = key;
+        let value_a = a.get(key).map(String::as_str).unwrap_or("<missing>");
+        let value_b = b.get(key).map(String::as_str).unwrap_or("<missing>");
+        if value_a != value_b {
+            differences += 1;
+            println!("{entity} {component}");
+            println!("  {path_a}: {value_a}");
+            println!("  {path_b}: {value_b}");
+        }
+    }
+
+    println!("{differences} differences");
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use bevy::prelude::*;
+use std::cmp::Ordering;
+
+/// Subdivisions of a map cell. Gameplay positions, sizes and directions are
+/// integers in these, because float math like square roots and trig can round
+/// differently on native and wasm builds, which is enough for peers to desync.
+/// They only become floats for rendering.
+pub const UNIT: i32 = 1 << 10;
+
+/// Converts a length in map cells to `UNIT`s, meant for constants
+pub const fn fixed(cells: f32) -> i32 {
+    (cells * UNIT as f32) as i32
+}
+
+/// Converts gameplay coordinates to world coordinates, for rendering
+pub fn to_world(value: IVec2) -> Vec2 {
+    value.as_vec2() / UNIT as f32
+}
+
+/// Length of a vector, rounded down
+pub fn length(value: IVec2) -> i32 {
+    let (x, y) = (value.x as i64, value.y as i64);
+    (x * x + y * y).isqrt() as i32
+}
+
+/// Scales a vector to be `UNIT` long, zero stays zero. Vectors much shorter
+/// than `UNIT` lose precision, so scale them up first.
+pub fn normalize(value: IVec2) -> IVec2 {
+    let length = length(value) as i64;
+    if length == 0 {
+        return IVec2::ZERO;
+    }
+    let (x, y) = (value.x as i64, value.y as i64);
+    IVec2::new(
+        (x * UNIT as i64 / length) as i32,
+        (y * UNIT as i64 / length) as i32,
+    )
+}
+
+/// Gets the index of the octant (45 degree sectors) a direction points into,
+/// starting from 0 (right) and going counter-clockwise
+pub fn octant(direction: IVec2) -> usize {
+    let (x, y) = (direction.x as i64, direction.y as i64);
+    // tan(22.5°), the slope where one octant turns into the next
+    let (slope_num, slope_den) = (4142, 10000);
+
+    if y.abs() * slope_den <= x.abs() * slope_num {
+        if x >= 0 { 0 } else { 4 }
+    } else if x.abs() * slope_den <= y.abs() * slope_num {
+        if y > 0 { 2 } else { 6 }
+    } else {
+        match (x > 0, y > 0) {
+            (true, true) => 1,
+            (false, true) => 3,
+            (false, false) => 5,
+            (true, false) => 7,
+        }
+    }
+}
+
+/// An exact fraction, for comparing how far along the way things happen
+#[derive(Clone, Copy, Debug)]
+pub struct Ratio {
+    num: i64,
+    /// Always positive
+    den: i64,
+}
+
+impl Ratio {
+    pub const ZERO: Ratio = Ratio { num: 0, den: 1 };
+    pub const ONE: Ratio = Ratio { num: 1, den: 1 };
+
+    pub fn new(num: i32, den: i32) -> Self {
+        let sign = den.signum() as i64;
+        Self {
+            num: num as i64 * sign,
+            den: den as i64 * sign,
+        }
+    }
+
+    /// `value` scaled by the fraction, rounded towards zero
+    pub fn scale(self, value: IVec2) -> IVec2 {
+        IVec2::new(
+            (value.x as i64 * self.num / self.den) as i32,
+            (value.y as i64 * self.num / self.den) as i32,
+        )
+    }
+}
+
+impl Ord for Ratio {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.num * other.den).cmp(&(other.num * self.den))
+    }
+}
+
+impl PartialOrd for Ratio {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ratio {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ratio {}
@@ -0,0 +1,733 @@
+use crate::{
+    Config,
+    components::*,
+    fixed::{Ratio, UNIT, fixed, length},
+    input::*,
+    rollback::*,
+    weapons::Weapon,
+};
+use bevy::prelude::*;
+use bevy_ggrs::prelude::*;
+use bevy_roll_safe::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+pub const MAP_SIZE: i32 = 41;
+pub const MAX_PLAYERS: usize = 8;
+
+/// Rollback frames per second, unless the match is played at another
+/// `MatchSettings::tick_rate`. Durations in frames, like the ones below and
+/// the weapon stats, are counted at this rate and scaled to the match's.
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Frames between the end of one round and the start of the next
+const ROUND_END_FRAMES: u32 = DEFAULT_TICK_RATE;
+
+/// Half the map, in `UNIT`s
+const MAP_LIMIT: i32 = MAP_SIZE * UNIT / 2;
+const PLAYER_WIDTH: i32 = fixed(0.5);
+const PLAYER_HEIGHT: i32 = fixed(1.0);
+const PLAYER_RADIUS: i32 = fixed(0.5);
+/// `UNIT`s per second at full speed
+const PLAYER_SPEED: i32 = fixed(6.);
+/// Weapon pickups spawned on the map every round
+const NUM_PICKUPS: usize = 4;
+pub const PICKUP_SIZE: i32 = fixed(0.6);
+/// Frames a player can't be hit for after being hit, so one burst doesn't
+/// take all their health at once
+const INVULNERABILITY_FRAMES: u32 = 30;
+/// Where bullets spawn relative to the player, for each octant `AimDir` can
+/// point into
+const MUZZLE_OFFSETS: [IVec2; 8] = [
+    IVec2::new(fixed(0.5), fixed(0.0)),    // right
+    IVec2::new(fixed(0.5), fixed(0.25)),   // up-right
+    IVec2::new(fixed(0.25), fixed(0.5)),   // up
+    IVec2::new(fixed(-0.4), fixed(0.3)),   // up-left
+    IVec2::new(fixed(-0.5), fixed(0.0)),   // left
+    IVec2::new(fixed(-0.4), fixed(-0.25)), // down-left
+    IVec2::new(fixed(-0.25), fixed(-0.5)), // down
+    IVec2::new(fixed(0.25), fixed(-0.25)), // down-right
+];
+
+/// The rollback part of the game: the map, players, bullets and rounds.
+///
+/// Doesn't depend on rendering, assets or input devices, so it can also be
+/// simulated headless. Sprites are added to the entities it spawns by the
+/// rendering systems in `main.rs`.
+pub struct GameplayPlugin;
+
+impl Plugin for GameplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            GgrsPlugin::<Config>::default(),
+            RollbackSchedulePlugin::new_ggrs(),
+        ))
+        .insert_resource(RollbackFrameRate(DEFAULT_TICK_RATE as usize))
+        .init_ggrs_state::<RollbackState>()
+        .register_rollback_state()
+        .init_resource::<RoundEndTimer>()
+        .init_resource::<Scores>()
+        .init_resource::<MatchStats>()
+        .init_resource::<FrameCount>()
+        .add_systems(
+            First,
+            sync_rollback_frame_rate.run_if(resource_exists_and_changed::<MatchSettings>),
+        )
+        .add_systems(
+            OnEnter(RollbackState::InRound),
+            (generate_map, spawn_players.after(generate_map)),
+        )
+        .add_systems(
+            RollbackUpdate,
+            (
+                move_players,
+                resolve_wall_collisions.after(move_players),
+                aim_players.after(move_players),
+                fire_bullets
+                    .after(aim_players)
+                    .after(reload_guns)
+                    .after(resolve_wall_collisions),
+                move_bullet.after(fire_bullets),
+                bullet_wall_collisions.after(move_bullet),
+                pick_up_weapons.after(resolve_wall_collisions),
+                reload_guns.after(pick_up_weapons),
+                tick_invulnerability,
+                // bullets that hit a wall are despawned by commands, but the
+                // part of their path before the wall can still hit players
+                damage_players
+                    .after_ignore_deferred(bullet_wall_collisions)
+                    .after(move_players)
+                    .after(tick_invulnerability),
+            )
+                .run_if(in_state(RollbackState::InRound))
+                .after(bevy_roll_safe::apply_state_transition::<RollbackState>),
+        )
+        .add_systems(
+            RollbackUpdate,
+            round_end_timeout
+                .run_if(in_state(RollbackState::RoundEnd))
+                .ambiguous_with(damage_players),
+        )
+        .add_systems(RollbackPostUpdate, increment_frame_count);
+    }
+}
+
+#[derive(States, Clone, Eq, PartialEq, Debug, Hash, Default)]
+pub enum RollbackState {
+    /// When the characters running and gunning
+    #[default]
+    InRound,
+    /// When only one character is left standing, and we're transitioning to the next round
+    RoundEnd,
+}
+
+/// Frames since the round ended, the next one starts after `ROUND_END_FRAMES`
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct RoundEndTimer(pub u32);
+
+/// Number of kills per player handle
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scores(pub [u32; MAX_PLAYERS]);
+
+impl Scores {
+    pub fn total(&self) -> u32 {
+        self.0.iter().sum()
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlayerStats {
+    pub kills: u32,
+    pub deaths: u32,
+    /// Deaths from your own bullets, only possible with `--self-damage`
+    pub self_kills: u32,
+    pub shots_fired: u32,
+}
+
+/// Stats for the whole match, indexed by player handle
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatchStats(pub [PlayerStats; MAX_PLAYERS]);
+
+#[derive(Resource, Default, Clone, Copy, Debug, Deref, DerefMut)]
+pub struct SessionSeed(pub u64);
+
+/// Number of rollback frames simulated so far, matches the GGRS frame number
+#[derive(Resource, Default, Clone, Copy, Debug)]
+pub struct FrameCount(pub i32);
+
+/// Number of players in the current session, handles are `0..NumPlayers`
+#[derive(Resource, Clone, Copy, Debug, Deref)]
+pub struct NumPlayers(pub usize);
+
+/// Rules of the current match. Every peer has to simulate with the same ones,
+/// so they're compared in the lobby instead of read from each peer's `Args`.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MatchSettings {
+    /// Players can be hit by their own bullets
+    pub self_damage: bool,
+    /// Health points players start each round with. A hit takes away the
+    /// damage of the weapon that fired the bullet
+    pub max_health: u32,
+    /// Bullets bounce off walls, as often as their weapon allows
+    pub ricochet: bool,
+    /// Rollback frames per second. Everything that takes time in the game is
+    /// counted in these frames, no matter how fast the game is rendered, so
+    /// peers only agree on what happens if they all tick at the same rate.
+    pub tick_rate: u32,
+}
+
+impl MatchSettings {
+    /// Converts a duration in frames at `DEFAULT_TICK_RATE` to frames at the
+    /// match's tick rate, rounding up so nothing becomes instant
+    pub fn frames(&self, default_frames: u32) -> u32 {
+        (default_frames * self.tick_rate).div_ceil(DEFAULT_TICK_RATE)
+    }
+}
+
+/// Has GGRS advance the session at the match's tick rate, before the rollback
+/// schedules run in `PreUpdate`
+fn sync_rollback_frame_rate(
+    settings: Res<MatchSettings>,
+    mut frame_rate: ResMut<RollbackFrameRate>,
+) {
+    frame_rate.0 = settings.tick_rate as usize;
+}
+
+fn generate_map(
+    mut commands: Commands,
+    walls: Query<Entity, With<Wall>>,
+    pickups: Query<Entity, With<WeaponPickup>>,
+    scores: Res<Scores>,
+    session_seed: Res<SessionSeed>,
+) {
+    // despawn walls and pickups from previous round (if any)
+    for entity in walls.iter().chain(&pickups) {
+        commands.entity(entity).despawn();
+    }
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(scores.total() as u64 ^ **session_seed);
+
+    // bottom left cell and size of each wall
+    let mut wall_cells = Vec::new();
+
+    for _ in 0..20 {
+        let max_box_size = MAP_SIZE / 4;
+        let width = rng.random_range(1..max_box_size);
+        let height = rng.random_range(1..max_box_size);
+
+        let cell_x = rng.random_range(0..=(MAP_SIZE - width));
+        let cell_y = rng.random_range(0..=(MAP_SIZE - height));
+
+        let size = IVec2::new(width, height);
+        let corner = IVec2::new(cell_x, cell_y) * UNIT - MAP_LIMIT;
+
+        commands.spawn((Wall { size }, Position(corner + size * UNIT / 2)));
+        wall_cells.push((IVec2::new(cell_x, cell_y), size));
+    }
+
+    let mut pickup_cells: Vec<IVec2> = Vec::new();
+
+    while pickup_cells.len() < NUM_PICKUPS {
+        let cell = IVec2::new(rng.random_range(0..MAP_SIZE), rng.random_range(0..MAP_SIZE));
+        let in_wall = wall_cells
+            .iter()
+            .any(|(corner, size)| cell.cmpge(*corner).all() && cell.cmplt(*corner + *size).all());
+        if in_wall || pickup_cells.contains(&cell) {
+            continue;
+        }
+        pickup_cells.push(cell);
+
+        let weapon = Weapon::PICKUPS[rng.random_range(0..Weapon::PICKUPS.len())];
+        let pos = cell * UNIT + UNIT / 2 - MAP_LIMIT;
+        commands
+            .spawn((WeaponPickup { weapon }, Position(pos)))
+            .add_rollback();
+    }
+}
+
+fn spawn_players(
+    mut commands: Commands,
+    players: Query<Entity, With<Player>>,
+    bullets: Query<Entity, With<Bullet>>,
+    scores: Res<Scores>,
+    session_seed: Res<SessionSeed>,
+    num_players: Res<NumPlayers>,
+    settings: Res<MatchSettings>,
+) {
+    info!("Spawning players");
+
+    for player in &players {
+        commands.entity(player).despawn();
+    }
+
+    for bullet in &bullets {
+        commands.entity(bullet).despawn();
+    }
+
+    let mut rng = Xoshiro256PlusPlus::seed_from_u64(scores.total() as u64 ^ **session_seed);
+
+    for handle in 0..**num_players {
+        let pos = IVec2::new(
+            rng.random_range(-MAP_LIMIT..MAP_LIMIT),
+            rng.random_range(-MAP_LIMIT..MAP_LIMIT),
+        );
+
+        commands
+            .spawn((
+                Player { handle },
+                Position(pos),
+                Weapon::default(),
+                Ammo::new(Weapon::default().stats().magazine_size),
+                Health::new(settings.max_health),
+                MoveDir(IVec2::new(-UNIT, 0)),
+                AimDir(IVec2::new(-UNIT, 0)),
+            ))
+            .add_rollback();
+    }
+}
+
+fn move_players(
+    mut players: Query<(&mut Position, &mut MoveDir, &mut DistanceTraveled, &Player)>,
+    inputs: Res<PlayerInputs<Config>>,
+    settings: Res<MatchSettings>,
+) {
+    for (mut position, mut move_direction, mut distance, player) in &mut players {
+        let (input, _) = inputs[player.handle];
+
+        let direction = direction(input);
+
+        if direction == IVec2::ZERO {
+            continue;
+        }
+
+        move_direction.0 = direction;
+
+        // direction is `UNIT` long, and speed is in quarters
+        let move_delta = direction * PLAYER_SPEED * speed_quarters(input)
+            / (UNIT * settings.tick_rate as i32 * 4);
+
+        let limit = IVec2::splat(MAP_LIMIT - fixed(0.5));
+        position.0 = (position.0 + move_delta).clamp(-limit, limit);
+
+        distance.0 += length(move_delta);
+    }
+}
+
+/// Players aim where they move, unless they aim somewhere else
+fn aim_players(
+    mut players: Query<(&mut AimDir, &MoveDir, &Player)>,
+    inputs: Res<PlayerInputs<Config>>,
+) {
+    for (mut aim_dir, move_dir, player) in &mut players {
+        let (input, _) = inputs[player.handle];
+        aim_dir.0 = aim(input).unwrap_or(move_dir.0);
+    }
+}
+
+fn resolve_wall_collisions(
+    mut players: Query<&mut Position, With<Player>>,
+    walls: Query<(&Position, &Wall), Without<Player>>,
+) {
+    for mut player_pos in &mut players {
+        for (wall_pos, wall) in &walls {
+            let wall_size = wall.size * UNIT;
+
+            let wall_to_player = player_pos.0 - wall_pos.0;
+            // exploit the symmetry of the problem,
+            // treat things as if they are in the first quadrant
+            let wall_to_player_abs = wall_to_player.abs();
+            let wall_corner_to_player_center = wall_to_player_abs - wall_size / 2;
+
+            let corner_to_corner = wall_corner_to_player_center - IVec2::splat(PLAYER_RADIUS);
+
+            if corner_to_corner.x > 0 || corner_to_corner.y > 0 {
+                // no collision
+                continue;
+            }
+
+            if corner_to_corner.x > corner_to_corner.y {
+                // least overlap on x axis
+                player_pos.0.x -= wall_to_player.x.signum() * corner_to_corner.x;
+            } else {
+                // least overlap on y axis
+                player_pos.0.y -= wall_to_player.y.signum() * corner_to_corner.y;
+            }
+        }
+    }
+}
+
+/// Players swap to whatever weapon they walk over, with a full magazine
+fn pick_up_weapons(
+    mut commands: Commands,
+    mut players: Query<(&Position, &Player, &mut Weapon, &mut Ammo)>,
+    pickups: Query<(Entity, &Position, &WeaponPickup)>,
+) {
+    // query order can differ between peers, so who gets a pickup when several
+    // players reach it at once is decided by handle, and which pickup a player
+    // gets when reaching several at once is decided by position
+    let mut players: Vec<_> = players.iter_mut().collect();
+    players.sort_by_key(|(_, player, ..)| player.handle);
+    let mut pickups: Vec<_> = pickups
+        .iter()
+        .map(|(entity, position, pickup)| (entity, position.0, *pickup))
+        .collect();
+    pickups.sort_by_key(|(_, pos, _)| (pos.x, pos.y));
+    let mut taken = Vec::new();
+
+    for (player_pos, _, mut weapon, mut ammo) in players {
+        for (pickup_entity, pickup_pos, pickup) in &pickups {
+            if taken.contains(pickup_entity) {
+                continue;
+            }
+
+            let distance = (player_pos.0 - *pickup_pos).abs();
+            if distance.x >= (PLAYER_WIDTH + PICKUP_SIZE) / 2
+                || distance.y >= (PLAYER_HEIGHT + PICKUP_SIZE) / 2
+            {
+                continue;
+            }
+
+            *weapon = pickup.weapon;
+            *ammo = Ammo::new(weapon.stats().magazine_size);
+            commands.entity(*pickup_entity).despawn();
+            taken.push(*pickup_entity);
+            break;
+        }
+    }
+}
+
+fn reload_guns(
+    inputs: Res<PlayerInputs<Config>>,
+    mut players: Query<(&mut Ammo, &Weapon, &Player)>,
+    settings: Res<MatchSettings>,
+) {
+    for (mut ammo, weapon, player) in &mut players {
+        let (input, _) = inputs[player.handle];
+
+        if !fire(input) {
+            ammo.trigger_released = true;
+        }
+
+        if ammo.cooldown_frames > 0 {
+            ammo.cooldown_frames -= 1;
+        }
+
+        if ammo.reloading() {
+            ammo.reload_frames -= 1;
+            if !ammo.reloading() {
+                ammo.rounds = ammo.magazine_size;
+            }
+        } else if ammo.rounds == 0 || (reload(input) && ammo.rounds < ammo.magazine_size) {
+            ammo.reload_frames = settings.frames(weapon.stats().reload_frames);
+        }
+    }
+}
+
+fn fire_bullets(
+    mut commands: Commands,
+    inputs: Res<PlayerInputs<Config>>,
+    mut players: Query<(&Position, &Player, &mut Ammo, &Weapon, &AimDir)>,
+    mut stats: ResMut<MatchStats>,
+    settings: Res<MatchSettings>,
+) {
+    // GGRS checksums entities by the order they were spawned in, and query
+    // order can differ between peers, so bullets are spawned by player handle
+    let mut players: Vec<_> = players.iter_mut().collect();
+    players.sort_by_key(|(_, player, ..)| player.handle);
+
+    for (player_pos, player, mut ammo, weapon, aim_dir) in players {
+        let (input, _) = inputs[player.handle];
+        if fire(input) && ammo.can_fire() {
+            let pos = player_pos.0 + MUZZLE_OFFSETS[aim_dir.octant()];
+            let weapon_stats = weapon.stats();
+            let bounces_left = if settings.ricochet {
+                weapon_stats.bounces
+            } else {
+                0
+            };
+            for direction in weapon_stats.pellet_directions(aim_dir.0) {
+                commands
+                    .spawn((
+                        Bullet {
+                            owner: player.handle,
+                            weapon: *weapon,
+                            frames_left: settings.frames(weapon_stats.lifetime_frames),
+                            bounces_left,
+                        },
+                        Position(pos),
+                        MoveDir(direction),
+                    ))
+                    .add_rollback();
+            }
+            ammo.rounds -= 1;
+            ammo.cooldown_frames = settings.frames(weapon_stats.cooldown_frames);
+            // automatic weapons don't need fire to be released between shots
+            ammo.trigger_released = weapon_stats.automatic;
+            stats.0[player.handle].shots_fired += 1;
+        }
+    }
+}
+
+fn move_bullet(
+    mut bullets: Query<(&mut Position, &mut PreviousPosition, &mut Bullet, &MoveDir)>,
+    settings: Res<MatchSettings>,
+) {
+    for (mut position, mut previous, mut bullet, dir) in &mut bullets {
+        previous.0 = position.0;
+        let speed = bullet.weapon.stats().bullet_speed;
+        // direction is `UNIT` long
+        position.0 += dir.0 * speed / (UNIT * settings.tick_rate as i32);
+        bullet.frames_left = bullet.frames_left.saturating_sub(1);
+    }
+}
+
+/// Where a moving point first enters a box
+struct SweepHit {
+    /// How far along the way the hit happened, from 0 to 1
+    time: Ratio,
+    /// Normal of the face that was hit, diagonal when hitting a corner exactly
+    normal: IVec2,
+    point: IVec2,
+}
+
+/// Sweeps a point from `from` to `to` through a box, slab by slab. The box
+/// boundary doesn't count as inside, and a point that starts inside hits at
+/// time 0.
+fn sweep(from: IVec2, to: IVec2, center: IVec2, half_size: IVec2) -> Option<SweepHit> {
+    let delta = to - from;
+    // `None` is before the start and after the end of time respectively
+    let mut enter: Option<Ratio> = None;
+    let mut exit: Option<Ratio> = None;
+    let mut normal = IVec2::ZERO;
+
+    for axis in 0..2 {
+        let min = center[axis] - half_size[axis];
+        let max = center[axis] + half_size[axis];
+        if delta[axis] == 0 {
+            if from[axis] <= min || from[axis] >= max {
+                return None;
+            }
+            continue;
+        }
+
+        let (near_edge, far_edge) = if delta[axis] > 0 {
+            (min, max)
+        } else {
+            (max, min)
+        };
+        let near = Ratio::new(near_edge - from[axis], delta[axis]);
+        let far = Ratio::new(far_edge - from[axis], delta[axis]);
+
+        let mut axis_normal = IVec2::ZERO;
+        axis_normal[axis] = -delta[axis].signum();
+        match enter {
+            Some(latest) if near < latest => {}
+            Some(latest) if near == latest => normal += axis_normal,
+            _ => {
+                enter = Some(near);
+                normal = axis_normal;
+            }
+        }
+        exit = Some(exit.map_or(far, |exit| exit.min(far)));
+    }
+
+    // not moving at all, but inside
+    let (Some(enter), Some(exit)) = (enter, exit) else {
+        return Some(SweepHit {
+            time: Ratio::ZERO,
+            normal,
+            point: from,
+        });
+    };
+
+    if enter >= exit || exit <= Ratio::ZERO || enter > Ratio::ONE {
+        return None;
+    }
+
+    if enter < Ratio::ZERO {
+        return Some(SweepHit {
+            time: Ratio::ZERO,
+            normal,
+            point: from,
+        });
+    }
+
+    // put the point exactly on the face, so a bullet bouncing off it doesn't
+    // end up inside the wall through rounding
+    let mut point = from + enter.scale(delta);
+    for axis in 0..2 {
+        if normal[axis] != 0 {
+            point[axis] = center[axis] + normal[axis] * half_size[axis];
+        }
+    }
+
+    Some(SweepHit {
+        time: enter,
+        normal,
+        point,
+    })
+}
+
+/// Despawns bullets that leave the map or run out of range, and bullets that
+/// hit a wall once they're out of bounces
+fn bullet_wall_collisions(
+    mut commands: Commands,
+    mut bullets: Query<(
+        Entity,
+        &mut Position,
+        &mut MoveDir,
+        &mut Bullet,
+        &PreviousPosition,
+    )>,
+    walls: Query<(&Position, &Wall), Without<Bullet>>,
+) {
+    for (bullet_entity, mut bullet_pos, mut move_dir, mut bullet, previous) in &mut bullets {
+        // the earliest hit wins, walls are sorted by position so everyone
+        // picks the same one if the bullet hits two at once
+        let hit = walls
+            .iter()
+            .filter_map(|(wall_pos, wall)| {
+                let half_size = wall.size * UNIT / 2;
+                sweep(previous.0, bullet_pos.0, wall_pos.0, half_size).map(|hit| (hit, wall_pos.0))
+            })
+            .min_by_key(|(hit, wall_pos)| (hit.time, wall_pos.x, wall_pos.y));
+
+        if let Some((hit, _)) = hit {
+            // stop at the wall, so players behind it don't get hit
+            bullet_pos.0 = hit.point;
+
+            if bullet.bounces_left == 0 {
+                commands.entity(bullet_entity).despawn();
+                continue;
+            }
+
+            // the rest of this frame's movement is lost, which is hardly noticeable
+            bullet.bounces_left -= 1;
+            let mut dir = move_dir.0;
+            if hit.normal.x != 0 {
+                dir.x = -dir.x;
+            }
+            if hit.normal.y != 0 {
+                dir.y = -dir.y;
+            }
+            move_dir.0 = dir;
+            continue;
+        }
+
+        if bullet.frames_left == 0
+            || bullet_pos.0.x.abs() > MAP_LIMIT
+            || bullet_pos.0.y.abs() > MAP_LIMIT
+        {
+            commands.entity(bullet_entity).despawn();
+        }
+    }
+}
+
+fn tick_invulnerability(mut players: Query<&mut Health>) {
+    for mut health in &mut players {
+        if health.invulnerable_frames > 0 {
+            health.invulnerable_frames -= 1;
+        }
+    }
+}
+
+fn damage_players(
+    mut commands: Commands,
+    mut players: Query<(Entity, &Position, &Player, &mut Health), Without<Bullet>>,
+    bullets: Query<(Entity, &Position, &PreviousPosition, &Bullet)>,
+    mut next_state: ResMut<NextState<RollbackState>>,
+    mut scores: ResMut<Scores>,
+    mut stats: ResMut<MatchStats>,
+    settings: Res<MatchSettings>,
+) {
+    let mut players_alive = players.iter().count();
+
+    let mut players: Vec<_> = players.iter_mut().collect();
+    players.sort_by_key(|(_, _, player, _)| player.handle);
+
+    // every bullet can only hit once, so find every possible hit and go
+    // through them from the earliest. Query order can differ between peers, so
+    // ties are broken by the order of players and bullets.
+    let mut hits = Vec::new();
+    for (bullet_entity, bullet_pos, previous, bullet) in &bullets {
+        let weapon_stats = bullet.weapon.stats();
+        let bullet_radius = weapon_stats.bullet_size.min_element() / 2;
+        let half_size = IVec2::new(PLAYER_WIDTH, PLAYER_HEIGHT) / 2 + bullet_radius;
+
+        for (i, (_, player_pos, player, _)) in players.iter().enumerate() {
+            let self_hit = bullet.owner == player.handle;
+            if self_hit && !settings.self_damage {
+                continue;
+            }
+
+            if let Some(hit) = sweep(previous.0, bullet_pos.0, player_pos.0, half_size) {
+                hits.push((hit.time, bullet_entity, bullet_pos.0, *bullet, i));
+            }
+        }
+    }
+    hits.sort_by_key(|(time, _, pos, bullet, player)| (*time, bullet.owner, pos.x, pos.y, *player));
+    let mut spent_bullets = Vec::new();
+
+    for (_, bullet_entity, _, bullet, i) in hits {
+        let (player_entity, _, player, health) = &mut players[i];
+
+        // also covers players that were killed earlier this frame
+        if health.invulnerable_frames > 0 {
+            continue;
+        }
+
+        if spent_bullets.contains(&bullet_entity) {
+            continue;
+        }
+
+        // the bullet may also have hit a wall this frame
+        commands.entity(bullet_entity).try_despawn();
+        spent_bullets.push(bullet_entity);
+
+        let weapon_stats = bullet.weapon.stats();
+        health.current = health.current.saturating_sub(weapon_stats.damage);
+        health.invulnerable_frames = settings.frames(INVULNERABILITY_FRAMES);
+
+        if health.current > 0 {
+            continue;
+        }
+
+        commands.entity(*player_entity).despawn();
+        players_alive -= 1;
+
+        stats.0[player.handle].deaths += 1;
+
+        // no points for shooting yourself
+        if bullet.owner == player.handle {
+            stats.0[player.handle].self_kills += 1;
+        } else {
+            scores.0[bullet.owner] += 1;
+            stats.0[bullet.owner].kills += 1;
+        }
+        info!(
+            "player {} killed by {}: {scores:?}",
+            player.handle, bullet.owner
+        );
+
+        // the round is over when there's only one player left standing
+        if players_alive <= 1 {
+            next_state.set(RollbackState::RoundEnd);
+        }
+    }
+}
+
+pub fn increment_frame_count(mut frame: ResMut<FrameCount>) {
+    frame.0 += 1;
+}
+
+fn round_end_timeout(
+    mut timer: ResMut<RoundEndTimer>,
+    mut state: ResMut<NextState<RollbackState>>,
+    settings: Res<MatchSettings>,
+) {
+    timer.0 += 1;
+
+    if timer.0 >= settings.frames(ROUND_END_FRAMES) {
+        *timer = default();
+        state.set(RollbackState::InRound);
+    }
+}
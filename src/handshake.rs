@@ -0,0 +1,217 @@
+use crate::{
+    Config, GameState, MatchmakingError,
+    args::Args,
+    lobby::{HANDSHAKE_CHANNEL, InputDelayProposal, Lobby, PeerRole},
+    start_p2p_session,
+};
+use bevy::prelude::*;
+use bevy_ggrs::ggrs::{self, PlayerType};
+use bevy_matchbox::prelude::*;
+use std::time::Duration;
+
+/// How long the ping is sampled for, once GGRS has stats for every player
+const SAMPLE_TIME: Duration = Duration::from_secs(2);
+/// Gives up measuring if the handshake session doesn't get going
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Used when the ping couldn't be measured
+const FALLBACK_INPUT_DELAY: usize = 2;
+/// Even on LAN, a frame of delay saves a lot of rollbacks
+const MIN_INPUT_DELAY: usize = 1;
+/// Input delay only covers latency up to this many frames, rollbacks hide the
+/// rest. Any more and the controls feel sluggish.
+const MAX_INPUT_DELAY: usize = 4;
+
+/// A throwaway GGRS session on its own channel, used to measure the round-trip
+/// time between players before the real session starts
+#[derive(Resource)]
+pub struct Handshake {
+    session: ggrs::P2PSession<Config>,
+    own_id: PeerId,
+    player_ids: Vec<PeerId>,
+    spectator_ids: Vec<PeerId>,
+    /// Round-trip time to the slowest player, in milliseconds, once per update
+    samples: Vec<u128>,
+    sample_timer: Timer,
+    timeout: Timer,
+    /// What we sent to the other players, once we've decided
+    proposal: Option<InputDelayProposal>,
+}
+
+pub fn start_handshake(
+    commands: &mut Commands,
+    socket: &mut MatchboxSocket,
+    own_id: PeerId,
+    player_ids: Vec<PeerId>,
+    spectator_ids: Vec<PeerId>,
+) {
+    info!("Measuring ping to the other players");
+
+    let mut session_builder =
+        ggrs::SessionBuilder::<Config>::new().with_num_players(player_ids.len());
+
+    for (handle, peer) in player_ids.iter().enumerate() {
+        let player = if *peer == own_id {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(*peer)
+        };
+        session_builder = session_builder
+            .add_player(player, handle)
+            .expect("failed to add player");
+    }
+
+    let channel = socket.take_channel(HANDSHAKE_CHANNEL).unwrap();
+    let session = session_builder
+        .start_p2p_session(channel)
+        .expect("failed to start handshake session");
+
+    commands.insert_resource(Handshake {
+        session,
+        own_id,
+        player_ids,
+        spectator_ids,
+        samples: Vec::new(),
+        sample_timer: Timer::new(SAMPLE_TIME, TimerMode::Once),
+        timeout: Timer::new(HANDSHAKE_TIMEOUT, TimerMode::Once),
+        proposal: None,
+    });
+}
+
+/// Every player proposes an input delay based on their own ping, and once all
+/// proposals are in, everyone picks the same one and starts the real session
+pub fn update_handshake(
+    mut commands: Commands,
+    mut handshake: ResMut<Handshake>,
+    mut socket: ResMut<MatchboxSocket>,
+    mut lobby: ResMut<Lobby>,
+    mut next_state: ResMut<NextState<GameState>>,
+    args: Res<Args>,
+    time: Res<Time<Real>>,
+) {
+    lobby.update(&mut socket, PeerRole::Player, args.match_settings());
+
+    if !lobby.everyone_ready(args.num_peers()) {
+        // the handshake channel is gone, so it takes a new socket to try again
+        let err = "a peer left before the match started".to_string();
+        warn!("{err}");
+        commands.remove_resource::<Handshake>();
+        commands.remove_resource::<MatchboxSocket>();
+        commands.insert_resource(MatchmakingError(err));
+        *lobby = default();
+        return;
+    }
+
+    // keep answering the other players, even when we're done measuring
+    handshake.session.poll_remote_clients();
+    for event in handshake.session.events() {
+        debug!("Handshake event: {event:?}");
+    }
+
+    if handshake.proposal.is_none() {
+        handshake.proposal = handshake.measure(&args, time.delta());
+        if let Some(proposal) = handshake.proposal {
+            lobby.send_input_delay(&mut socket, proposal);
+        }
+    }
+
+    let Some(own_proposal) = handshake.proposal else {
+        return;
+    };
+
+    let peer_proposals: Option<Vec<_>> = lobby
+        .peers_with_role(PeerRole::Player)
+        .iter()
+        .map(|peer| lobby.peers[peer].input_delay)
+        .collect();
+    let Some(mut proposals) = peer_proposals else {
+        return; // still waiting for someone
+    };
+    proposals.push(own_proposal);
+
+    let input_delay = agree_on_input_delay(&proposals);
+    info!("Playing with {input_delay} frames of input delay");
+
+    let Handshake {
+        own_id,
+        player_ids,
+        spectator_ids,
+        ..
+    } = handshake.as_mut();
+    start_p2p_session(
+        &mut commands,
+        &mut socket,
+        *own_id,
+        std::mem::take(player_ids),
+        std::mem::take(spectator_ids),
+        input_delay,
+        args.tick_rate,
+    );
+    commands.remove_resource::<Handshake>();
+    next_state.set(GameState::InGame);
+}
+
+impl Handshake {
+    fn measure(&mut self, args: &Args, delta: Duration) -> Option<InputDelayProposal> {
+        if let Some(frames) = args.input_delay {
+            return Some(InputDelayProposal {
+                frames,
+                forced: true,
+            });
+        }
+
+        if self.timeout.tick(delta).is_finished() {
+            warn!("Couldn't measure ping, using {FALLBACK_INPUT_DELAY} frames of input delay");
+            return Some(InputDelayProposal {
+                frames: FALLBACK_INPUT_DELAY,
+                forced: false,
+            });
+        }
+
+        // GGRS only has stats for a player about a second after synchronizing
+        let pings: Result<Vec<_>, _> = self
+            .session
+            .remote_player_handles()
+            .into_iter()
+            .map(|handle| self.session.network_stats(handle).map(|stats| stats.ping))
+            .collect();
+        let Ok(pings) = pings else {
+            return None;
+        };
+        self.samples
+            .push(pings.into_iter().max().unwrap_or_default());
+
+        if !self.sample_timer.tick(delta).is_finished() {
+            return None;
+        }
+
+        let rtt = self.samples.iter().sum::<u128>() / self.samples.len() as u128;
+        let frames = input_delay_for_rtt(rtt, args.tick_rate);
+        info!("Ping to the slowest player is {rtt} ms, proposing {frames} frames of input delay");
+        Some(InputDelayProposal {
+            frames,
+            forced: false,
+        })
+    }
+}
+
+/// Enough delay for inputs to arrive before they're needed, up to a point
+fn input_delay_for_rtt(rtt: u128, tick_rate: u32) -> usize {
+    let frame_millis = 1000. / tick_rate as f32;
+    let one_way_frames = rtt as f32 / 2. / frame_millis;
+    (one_way_frames.round() as usize).clamp(MIN_INPUT_DELAY, MAX_INPUT_DELAY)
+}
+
+/// Every player ends up with the same proposals, so they all agree. The
+/// slowest connection decides, unless someone forced a value.
+fn agree_on_input_delay(proposals: &[InputDelayProposal]) -> usize {
+    let highest = |forced: bool| {
+        proposals
+            .iter()
+            .filter(|proposal| proposal.forced == forced)
+            .map(|proposal| proposal.frames)
+            .max()
+    };
+    highest(true)
+        .or_else(|| highest(false))
+        .unwrap_or(FALLBACK_INPUT_DELAY)
+}
@@ -0,0 +1,213 @@
+use crate::{
+    Config,
+    args::Args,
+    desync::{DesyncHistory, FrameChecksums, record_checksum, record_snapshot},
+    gameplay::*,
+    input::random_inputs,
+};
+use bevy::{log::LogPlugin, prelude::*, state::app::StatesPlugin, time::TimeUpdateStrategy};
+use bevy_ggrs::{ReadInputs, Session, ggrs};
+use bevy_roll_safe::prelude::*;
+use std::time::Duration;
+
+const DEFAULT_FRAMES: i32 = 3600;
+const DEFAULT_SEED: u64 = 0;
+
+/// How far back the rollback run rolls back, every single frame
+const CHECK_DISTANCE: usize = 7;
+
+struct Outcome {
+    checksums: Vec<u64>,
+    scores: Scores,
+    stats: MatchStats,
+}
+
+/// Simulates `--frames` frames twice with the same random inputs: once straight
+/// through, and once rolling back every frame. Fails if re-simulating a frame
+/// changes it, if the two runs ever end up in different states, or if the
+/// match stats don't add up.
+pub fn run(args: &Args) -> Result<(), String> {
+    let frames = args.frames.unwrap_or(DEFAULT_FRAMES);
+    let seed = args.seed.unwrap_or(DEFAULT_SEED);
+
+    let rollback = simulate(args, frames, seed, CHECK_DISTANCE)?;
+    let straight = simulate(args, frames, seed, 0)?;
+
+    if let Some(i) = straight
+        .checksums
+        .iter()
+        .zip(&rollback.checksums)
+        .position(|(a, b)| a != b)
+    {
+        return Err(format!("rolling back changed the state on frame {}", i + 1));
+    }
+
+    let Outcome {
+        checksums,
+        scores,
+        stats,
+    } = straight;
+
+    println!(
+        "simulated {} frames with {} players and seed {}",
+        checksums.len(),
+        args.players,
+        seed
+    );
+    for (handle, player_stats) in stats.0[..args.players].iter().enumerate() {
+        println!(
+            "player {}: {} kills, {} deaths, {} self kills, {} shots",
+            handle + 1,
+            player_stats.kills,
+            player_stats.deaths,
+            player_stats.self_kills,
+            player_stats.shots_fired
+        );
+    }
+    println!("final checksum: {:016X}", checksums.last().unwrap_or(&0));
+
+    check_stats(&scores, &stats)
+}
+
+/// Every death is either someone else's kill, or a self kill
+fn check_stats(scores: &Scores, stats: &MatchStats) -> Result<(), String> {
+    let total = |stat: fn(&PlayerStats) -> u32| stats.0.iter().map(stat).sum::<u32>();
+    let kills = total(|s| s.kills);
+    let deaths = total(|s| s.deaths);
+    let self_kills = total(|s| s.self_kills);
+    if kills != scores.total() || kills + self_kills != deaths {
+        return Err(format!(
+            "stats don't add up: {kills} kills, {deaths} deaths, {self_kills} self kills, scores {:?}",
+            scores.0
+        ));
+    }
+
+    Ok(())
+}
+
+fn simulate(args: &Args, frames: i32, seed: u64, check_distance: usize) -> Result<Outcome, String> {
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, StatesPlugin, GameplayPlugin))
+        // step time by a fixed amount instead of by the wall clock, so we
+        // simulate as fast as possible
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1. / args.tick_rate as f64,
+        )))
+        .insert_resource(SessionSeed(seed))
+        .insert_resource(NumPlayers(args.players))
+        .insert_resource(args.match_settings())
+        .init_resource::<DesyncHistory>()
+        .init_resource::<FrameChecksums>()
+        .add_systems(ReadInputs, random_inputs)
+        .add_systems(
+            RollbackPostUpdate,
+            (record_snapshot, record_checksum)
+                .chain()
+                .after(increment_frame_count),
+        );
+
+    if check_distance > 0 {
+        // only one app can install the global logger, and this is the run
+        // where GGRS reports checksum mismatches
+        app.add_plugins(LogPlugin::default());
+    }
+
+    let mut session_builder = ggrs::SessionBuilder::<Config>::new()
+        .with_num_players(args.players)
+        .with_check_distance(check_distance);
+
+    for handle in 0..args.players {
+        session_builder = session_builder
+            .add_player(ggrs::PlayerType::Local, handle)
+            .map_err(|err| format!("failed to add player: {err}"))?;
+    }
+
+    let session = session_builder
+        .start_synctest_session()
+        .map_err(|err| format!("failed to start session: {err}"))?;
+    app.insert_resource(Session::SyncTest(session));
+
+    app.finish();
+    app.cleanup();
+
+    // time steps don't line up exactly with frames, so some updates don't
+    // advance a frame
+    for _ in 0..frames * 2 {
+        if app.world().resource::<FrameCount>().0 >= frames {
+            break;
+        }
+        app.update();
+    }
+
+    let frame = app.world().resource::<FrameCount>().0;
+    if frame < frames {
+        return Err(format!("simulation stalled on frame {frame}"));
+    }
+
+    let world = app.world_mut();
+    let checksums = world.remove_resource::<FrameChecksums>().unwrap();
+    if let Some(frame) = checksums.mismatched_frames.first() {
+        return Err(format!("frame {frame} changed when re-simulated"));
+    }
+
+    Ok(Outcome {
+        checksums: checksums.checksums,
+        scores: *world.resource::<Scores>(),
+        stats: *world.resource::<MatchStats>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const FRAMES: i32 = 2400;
+
+    fn args(flags: &[&str]) -> Args {
+        Args::parse_from(["extreme_bevy", "--headless"].iter().chain(flags))
+    }
+
+    fn assert_rollback_matches_straight_run(args: &Args, seed: u64) {
+        let rollback = simulate(args, FRAMES, seed, CHECK_DISTANCE).unwrap();
+        let straight = simulate(args, FRAMES, seed, 0).unwrap();
+
+        assert_eq!(straight.checksums.len(), FRAMES as usize);
+        assert_eq!(rollback.checksums, straight.checksums);
+        assert_eq!(rollback.scores, straight.scores);
+        assert_eq!(rollback.stats, straight.stats);
+
+        check_stats(&straight.scores, &straight.stats).unwrap();
+        let shots: u32 = straight.stats.0.iter().map(|s| s.shots_fired).sum();
+        assert!(shots > 0, "nobody fired in {FRAMES} frames");
+    }
+
+    #[test]
+    fn rolling_back_doesnt_change_the_outcome() {
+        assert_rollback_matches_straight_run(&args(&[]), 0);
+    }
+
+    #[test]
+    fn rolling_back_doesnt_change_the_outcome_with_match_settings() {
+        let args = args(&[
+            "--players=4",
+            "--self-damage",
+            "--ricochet",
+            "--max-health=1",
+            "--tick-rate=30",
+        ]);
+        assert_rollback_matches_straight_run(&args, 1);
+    }
+
+    #[test]
+    fn same_seed_plays_out_the_same() {
+        let args = args(&[]);
+        let a = simulate(&args, FRAMES, 2, 0).unwrap();
+        let b = simulate(&args, FRAMES, 2, 0).unwrap();
+        let other_seed = simulate(&args, FRAMES, 3, 0).unwrap();
+
+        assert_eq!(a.checksums, b.checksums);
+        assert_eq!(a.scores, b.scores);
+        assert_ne!(a.checksums, other_seed.checksums);
+    }
+}
@@ -1,62 +1,397 @@
-use crate::Config;
-use bevy::{platform::collections::HashMap, prelude::*};
+use crate::{
+    Config,
+    bindings::{KEYBOARD_SLOTS, KeyBindings},
+    components::{AimDir, Player},
+    fixed::{UNIT, normalize},
+    gameplay::{FrameCount, SessionSeed},
+    touch::touch_input,
+};
+use bevy::{platform::collections::HashMap, prelude::*, window::PrimaryWindow};
 use bevy_ggrs::{LocalInputs, LocalPlayers};
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256PlusPlus;
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
 
-const INPUT_UP: u8 = 1 << 0;
-const INPUT_DOWN: u8 = 1 << 1;
-const INPUT_LEFT: u8 = 1 << 2;
-const INPUT_RIGHT: u8 = 1 << 3;
-const INPUT_FIRE: u8 = 1 << 4;
+const INPUT_UP: u8 = Action::Up.bit();
+const INPUT_DOWN: u8 = Action::Down.bit();
+const INPUT_LEFT: u8 = Action::Left.bit();
+const INPUT_RIGHT: u8 = Action::Right.bit();
+const INPUT_FIRE: u8 = Action::Fire.bit();
+const INPUT_RELOAD: u8 = Action::Reload.bit();
+/// Analog stick tilt in quarters, 0 means full speed so digital input doesn't
+/// have to set anything. Goes in the two bits after the actions.
+const INPUT_SPEED_SHIFT: u8 = Action::ALL.len() as u8 - 1;
+const INPUT_SPEED_MASK: u8 = 0b11 << INPUT_SPEED_SHIFT;
+
+/// Stick tilt below this is ignored
+const STICK_DEADZONE: f32 = 0.25;
+
+/// How many frames each random input is held, so players actually get somewhere
+const RANDOM_INPUT_HOLD_FRAMES: i32 = 20;
+
+/// Everything a player does in one frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PlayerInput {
+    /// Movement, fire, reload and speed, see `INPUT_*`
+    pub buttons: u8,
+    /// Aim direction, quantized to integers so every peer normalizes it to the
+    /// exact same vector. Zero when not aiming.
+    pub aim_x: i8,
+    pub aim_y: i8,
+}
+
+impl PlayerInput {
+    /// Size of an input in replays and lobby messages
+    pub const BYTES: usize = 3;
+
+    pub fn to_bytes(self) -> [u8; Self::BYTES] {
+        [self.buttons, self.aim_x as u8, self.aim_y as u8]
+    }
+
+    pub fn from_bytes([buttons, aim_x, aim_y]: [u8; Self::BYTES]) -> Self {
+        Self {
+            buttons,
+            aim_x: aim_x as i8,
+            aim_y: aim_y as i8,
+        }
+    }
+}
+
+/// Something a player can do, each one is a bit in the input, except for aim
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    Fire,
+    Reload,
+    /// Keeps aiming in the same direction while held
+    Aim,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::Up,
+        Action::Down,
+        Action::Left,
+        Action::Right,
+        Action::Fire,
+        Action::Reload,
+        Action::Aim,
+    ];
+
+    /// The bit this action sets in the input, which is its place in `ALL`.
+    /// Aim is sent as a direction instead, so it comes last and has none.
+    pub const fn bit(self) -> u8 {
+        if let Action::Aim = self {
+            return 0;
+        }
+        let mut index = 0;
+        while Action::ALL[index] as u8 != self as u8 {
+            index += 1;
+        }
+        1 << index
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Up => "up",
+            Action::Down => "down",
+            Action::Left => "left",
+            Action::Right => "right",
+            Action::Fire => "fire",
+            Action::Reload => "reload",
+            Action::Aim => "aim",
+        }
+    }
+}
+
+/// Which gamepad controls which local player handle
+#[derive(Resource, Default, Debug)]
+pub struct GamepadAssignments(pub HashMap<usize, Entity>);
+
+/// Gives each connected gamepad to a local player that doesn't have one yet
+pub fn assign_gamepads(
+    mut assignments: ResMut<GamepadAssignments>,
+    local_players: Res<LocalPlayers>,
+    gamepads: Query<Entity, With<Gamepad>>,
+) {
+    // forget disconnected gamepads, and players from previous matches
+    assignments
+        .0
+        .retain(|handle, gamepad| local_players.0.contains(handle) && gamepads.contains(*gamepad));
+
+    let mut unassigned = gamepads
+        .iter()
+        .filter(|gamepad| !assignments.0.values().any(|assigned| assigned == gamepad))
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    for handle in &local_players.0 {
+        if assignments.0.contains_key(handle) {
+            continue;
+        }
+        let Some(gamepad) = unassigned.next() else {
+            break;
+        };
+        info!("Gamepad {gamepad} controls player {handle}");
+        assignments.0.insert(*handle, gamepad);
+    }
+}
 
 pub fn read_local_inputs(
     mut commands: Commands,
     keys: Res<ButtonInput<KeyCode>>,
+    bindings: Res<KeyBindings>,
+    gamepads: Query<&Gamepad>,
+    assignments: Res<GamepadAssignments>,
+    touches: Res<Touches>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    players: Query<(&Player, &Transform, &AimDir)>,
     local_players: Res<LocalPlayers>,
+    mut aim_locks: Local<HashMap<usize, Vec2>>,
 ) {
     let mut local_inputs = HashMap::new();
 
-    for handle in &local_players.0 {
+    let cursor = windows
+        .single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+        .zip(cameras.single().ok())
+        .and_then(|(cursor, (camera, camera_transform))| {
+            camera.viewport_to_world_2d(camera_transform, cursor).ok()
+        });
+
+    // players sharing the keyboard each get their own keys, but a single
+    // player can use all of them
+    let shared_keyboard = local_players.0.len() > 1;
+
+    for (i, handle) in local_players.0.iter().enumerate() {
         let mut input = 0u8;
 
-        if keys.any_pressed([KeyCode::ArrowUp, KeyCode::KeyW]) {
-            input |= INPUT_UP;
+        let slots = if shared_keyboard {
+            i..i + 1
+        } else {
+            0..KEYBOARD_SLOTS
+        };
+        for action in Action::ALL {
+            let action_keys = slots
+                .clone()
+                .flat_map(|slot| bindings.keys(slot, action).iter().copied());
+            if keys.any_pressed(action_keys) {
+                input |= action.bit();
+            }
         }
-        if keys.any_pressed([KeyCode::ArrowDown, KeyCode::KeyS]) {
-            input |= INPUT_DOWN;
-        }
-        if keys.any_pressed([KeyCode::ArrowLeft, KeyCode::KeyA]) {
-            input |= INPUT_LEFT
-        }
-        if keys.any_pressed([KeyCode::ArrowRight, KeyCode::KeyD]) {
-            input |= INPUT_RIGHT;
+
+        let player = players.iter().find(|(player, ..)| player.handle == *handle);
+
+        // the aim key keeps the direction the player was facing when it was pressed
+        let aim_keys = slots
+            .clone()
+            .flat_map(|slot| bindings.keys(slot, Action::Aim).iter().copied());
+        let mut aim = if keys.any_pressed(aim_keys)
+            && let Some((_, _, aim_dir)) = player
+        {
+            Some(*aim_locks.entry(*handle).or_insert(aim_dir.0.as_vec2()))
+        } else {
+            aim_locks.remove(handle);
+            None
+        };
+
+        let gamepad = assignments
+            .0
+            .get(handle)
+            .and_then(|gamepad| gamepads.get(*gamepad).ok());
+        if let Some(gamepad) = gamepad {
+            input |= gamepad_input(gamepad);
+            aim = gamepad_aim(gamepad).or(aim);
         }
-        if keys.any_pressed([KeyCode::Space, KeyCode::Enter]) {
-            input |= INPUT_FIRE;
+
+        // there's only one mouse and touch screen, they belong to the first local player
+        if i == 0 {
+            if let Ok(window) = windows.single() {
+                input |= touch_input(&touches, window);
+            }
+
+            // left click fires towards the cursor, right click only aims
+            if mouse.any_pressed([MouseButton::Left, MouseButton::Right])
+                && let Some((cursor, (_, transform, _))) = cursor.zip(player)
+            {
+                aim = Some(cursor - transform.translation.xy());
+            }
+            if mouse.pressed(MouseButton::Left) {
+                input |= INPUT_FIRE;
+            }
         }
 
-        local_inputs.insert(*handle, input);
+        let (aim_x, aim_y) = aim.map_or((0, 0), quantize_aim);
+        local_inputs.insert(
+            *handle,
+            PlayerInput {
+                buttons: input,
+                aim_x,
+                aim_y,
+            },
+        );
+    }
+
+    commands.insert_resource(LocalInputs::<Config>(local_inputs));
+}
+
+fn gamepad_input(gamepad: &Gamepad) -> u8 {
+    let mut input = 0u8;
+
+    if gamepad.pressed(GamepadButton::DPadUp) {
+        input |= INPUT_UP;
+    }
+    if gamepad.pressed(GamepadButton::DPadDown) {
+        input |= INPUT_DOWN;
+    }
+    if gamepad.pressed(GamepadButton::DPadLeft) {
+        input |= INPUT_LEFT;
+    }
+    if gamepad.pressed(GamepadButton::DPadRight) {
+        input |= INPUT_RIGHT;
+    }
+    if input == 0 {
+        input = quantize_stick(gamepad.left_stick());
+    }
+
+    if gamepad.any_pressed([
+        GamepadButton::South,
+        GamepadButton::RightTrigger,
+        GamepadButton::RightTrigger2,
+    ]) {
+        input |= INPUT_FIRE;
+    }
+
+    if gamepad.pressed(GamepadButton::West) {
+        input |= INPUT_RELOAD;
+    }
+
+    input
+}
+
+fn gamepad_aim(gamepad: &Gamepad) -> Option<Vec2> {
+    let stick = gamepad.right_stick();
+    (stick.length() >= STICK_DEADZONE).then_some(stick)
+}
+
+/// Scales the direction to fit in an `i8`, zero stays zero
+fn quantize_aim(direction: Vec2) -> (i8, i8) {
+    let direction = direction.normalize_or_zero() * i8::MAX as f32;
+    (direction.x.round() as i8, direction.y.round() as i8)
+}
+
+/// Snaps the stick to one of 8 directions and 4 speeds. Floats never make it
+/// into the rollback state, so peers can't disagree on what the stick meant.
+pub fn quantize_stick(stick: Vec2) -> u8 {
+    let tilt = stick.length().min(1.);
+    if tilt < STICK_DEADZONE {
+        return 0;
     }
 
+    // counter-clockwise from right, like `fixed::octant`
+    let octant = ((stick.to_angle() / (PI / 4.)).round() as i32).rem_euclid(8);
+    let direction = [
+        INPUT_RIGHT,
+        INPUT_RIGHT | INPUT_UP,
+        INPUT_UP,
+        INPUT_UP | INPUT_LEFT,
+        INPUT_LEFT,
+        INPUT_LEFT | INPUT_DOWN,
+        INPUT_DOWN,
+        INPUT_DOWN | INPUT_RIGHT,
+    ][octant as usize];
+
+    // 1 to 4 quarters, where 4 is full speed and encoded as 0
+    let quarters = ((tilt - STICK_DEADZONE) / (1. - STICK_DEADZONE) * 4.).ceil() as u8;
+    let speed = quarters.clamp(1, 4) % 4;
+
+    direction | (speed << INPUT_SPEED_SHIFT)
+}
+
+/// Random combinations of buttons for fuzzing the simulation, derived from the
+/// session seed so runs can be reproduced
+pub fn random_inputs(
+    mut commands: Commands,
+    local_players: Res<LocalPlayers>,
+    frame: Res<FrameCount>,
+    seed: Res<SessionSeed>,
+) {
+    let hold = (frame.0 / RANDOM_INPUT_HOLD_FRAMES) as u64;
+
+    let local_inputs = local_players
+        .0
+        .iter()
+        .map(|handle| {
+            let mut rng =
+                Xoshiro256PlusPlus::seed_from_u64(**seed ^ ((hold << 8) | *handle as u64));
+            let buttons = INPUT_UP
+                | INPUT_DOWN
+                | INPUT_LEFT
+                | INPUT_RIGHT
+                | INPUT_FIRE
+                | INPUT_RELOAD
+                | INPUT_SPEED_MASK;
+            let (aim_x, aim_y) = if rng.random() {
+                (rng.random(), rng.random())
+            } else {
+                (0, 0)
+            };
+            let input = PlayerInput {
+                buttons: rng.random::<u8>() & buttons,
+                aim_x,
+                aim_y,
+            };
+            (*handle, input)
+        })
+        .collect();
+
     commands.insert_resource(LocalInputs::<Config>(local_inputs));
 }
 
-pub fn direction(input: u8) -> Vec2 {
-    let mut direction = Vec2::ZERO;
-    if input & INPUT_UP != 0 {
-        direction.y += 1.;
+/// Normalized move direction, `UNIT` long, or zero when not moving
+pub fn direction(input: PlayerInput) -> IVec2 {
+    let buttons = input.buttons;
+    let mut direction = IVec2::ZERO;
+    if buttons & INPUT_UP != 0 {
+        direction.y += 1;
     }
-    if input & INPUT_DOWN != 0 {
-        direction.y -= 1.;
+    if buttons & INPUT_DOWN != 0 {
+        direction.y -= 1;
     }
-    if input & INPUT_RIGHT != 0 {
-        direction.x += 1.;
+    if buttons & INPUT_RIGHT != 0 {
+        direction.x += 1;
     }
-    if input & INPUT_LEFT != 0 {
-        direction.x -= 1.;
+    if buttons & INPUT_LEFT != 0 {
+        direction.x -= 1;
+    }
+    normalize(direction * UNIT)
+}
+
+/// Quarters of full speed to move at, from 1 to 4
+pub fn speed_quarters(input: PlayerInput) -> i32 {
+    match (input.buttons & INPUT_SPEED_MASK) >> INPUT_SPEED_SHIFT {
+        0 => 4,
+        quarters => quarters as i32,
     }
-    direction.normalize_or_zero()
 }
 
-pub fn fire(input: u8) -> bool {
-    input & INPUT_FIRE != 0
+pub fn fire(input: PlayerInput) -> bool {
+    input.buttons & INPUT_FIRE != 0
+}
+
+pub fn reload(input: PlayerInput) -> bool {
+    input.buttons & INPUT_RELOAD != 0
+}
+
+/// Normalized aim direction, `UNIT` long, if the player is aiming
+pub fn aim(input: PlayerInput) -> Option<IVec2> {
+    let aim = IVec2::new(input.aim_x as i32, input.aim_y as i32);
+    (aim != IVec2::ZERO).then(|| normalize(aim * UNIT))
 }
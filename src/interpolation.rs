@@ -0,0 +1,62 @@
+use crate::{components::Position, fixed::to_world, gameplay::FrameCount};
+use bevy::prelude::*;
+use bevy_ggrs::RollbackFrameRate;
+
+/// Where an entity was after the last two rollback frames, in world
+/// coordinates.
+///
+/// Rollback frames tick at a fixed rate, no matter how often we render, so
+/// entities are drawn in between the two instead of jumping from frame to
+/// frame.
+#[derive(Component, Debug)]
+pub struct Interpolated {
+    previous: Vec2,
+    current: Vec2,
+}
+
+/// How long ago the last rollback frame was simulated
+#[derive(Resource, Default, Debug)]
+pub struct TickProgress {
+    frame: i32,
+    elapsed: f32,
+}
+
+/// Moves `Transform` along with `Position`, one rollback frame behind so
+/// there's always a newer position to move towards. Draw order is left alone.
+pub fn interpolate_transforms(
+    mut commands: Commands,
+    mut progress: ResMut<TickProgress>,
+    frame: Res<FrameCount>,
+    frame_rate: Res<RollbackFrameRate>,
+    time: Res<Time>,
+    mut entities: Query<(Entity, &Position, &mut Transform, Option<&mut Interpolated>)>,
+) {
+    let ticked = frame.0 != progress.frame;
+    if ticked {
+        progress.frame = frame.0;
+        progress.elapsed = 0.;
+    } else {
+        progress.elapsed += time.delta_secs();
+    }
+    let t = (progress.elapsed * frame_rate.0 as f32).min(1.);
+
+    for (entity, position, mut transform, interpolated) in &mut entities {
+        let position = to_world(position.0);
+
+        let Some(mut interpolated) = interpolated else {
+            commands.entity(entity).insert(Interpolated {
+                previous: position,
+                current: position,
+            });
+            transform.translation = position.extend(transform.translation.z);
+            continue;
+        };
+
+        if ticked {
+            interpolated.previous = interpolated.current;
+            interpolated.current = position;
+        }
+        let drawn = interpolated.previous.lerp(interpolated.current, t);
+        transform.translation = drawn.extend(transform.translation.z);
+    }
+}
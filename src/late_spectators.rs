@@ -0,0 +1,169 @@
+use crate::{
+    Config, FrameCount, GameState, MatchOver, MatchSettings, NumPlayers, SessionSeed,
+    lobby::{Lobby, MatchInProgress, PeerRole},
+    replay::{Replay, ReplayRecorder},
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_ggrs::{RollbackFrameRate, Session, ggrs};
+use bevy_matchbox::prelude::*;
+use std::time::Duration;
+
+/// Once a late spectator is a second behind, it fast forwards to catch up
+const CATCH_UP_SECONDS: f32 = 1.;
+const CATCH_UP_SPEED: f32 = 8.;
+
+/// Peers that joined the room after the match started, and how many frames of
+/// inputs we've sent them. Only the host has this.
+///
+/// Spectators that were there when the match started get their inputs from
+/// GGRS, but a GGRS session can't add spectators once it's running, so the
+/// host streams confirmed inputs to the others over the lobby channel. They
+/// simulate the match from the start, like a replay that's still being
+/// recorded.
+#[derive(Resource, Default, Debug)]
+pub struct LateSpectators(HashMap<PeerId, usize>);
+
+/// Following a match that started before we joined
+pub fn late_spectating(lobby: Res<Lobby>) -> bool {
+    lobby.match_in_progress.is_some()
+}
+
+pub fn serve_late_spectators(
+    mut late_spectators: ResMut<LateSpectators>,
+    mut lobby: ResMut<Lobby>,
+    mut socket: ResMut<MatchboxSocket>,
+    session: Res<Session<Config>>,
+    recorder: Res<ReplayRecorder>,
+    seed: Res<SessionSeed>,
+    num_players: Res<NumPlayers>,
+    settings: Res<MatchSettings>,
+) {
+    let Session::P2P(session) = session.as_ref() else {
+        return;
+    };
+
+    lobby.update(&mut socket, PeerRole::Player, *settings);
+    late_spectators
+        .0
+        .retain(|peer, _| lobby.peers.contains_key(peer));
+
+    let info = MatchInProgress {
+        seed: **seed,
+        num_players: **num_players,
+        settings: *settings,
+    };
+    for (peer, lobby_peer) in &lobby.peers {
+        let introduced = lobby_peer.role.is_some();
+        let in_session = !session.handles_by_address(*peer).is_empty();
+        if introduced && !in_session && !late_spectators.0.contains_key(peer) {
+            info!("{peer} joined after the match started, streaming inputs to it");
+            lobby.send_match_in_progress(&mut socket, *peer, info);
+            late_spectators.0.insert(*peer, 0);
+        }
+    }
+
+    for (peer, sent) in &mut late_spectators.0 {
+        // players that are too late to play are only told the match started
+        if lobby.peers[peer].role != Some(PeerRole::Spectator) {
+            continue;
+        }
+
+        // confirmed inputs don't change anymore, so each frame is only sent once
+        let new_frames: Vec<_> = recorder.confirmed_from(*sent).cloned().collect();
+        if new_frames.is_empty() {
+            continue;
+        }
+        lobby.send_inputs(&mut socket, *peer, *sent, &new_frames);
+        *sent += new_frames.len();
+    }
+}
+
+/// Starts simulating a match the host told us is already in progress, with
+/// the inputs it streams to us
+pub fn start_late_spectating(
+    commands: &mut Commands,
+    lobby: &mut Lobby,
+    next_state: &mut NextState<GameState>,
+    time: &mut Time<Virtual>,
+) {
+    let Some((host, info)) = lobby.match_in_progress else {
+        return;
+    };
+    info!("Spectating match hosted by {host}, which has already started");
+
+    // a synctest session with check distance 0 never rolls back, it just
+    // advances with the inputs we give it
+    let mut session_builder = ggrs::SessionBuilder::<Config>::new()
+        .with_num_players(info.num_players)
+        .with_check_distance(0);
+
+    for handle in 0..info.num_players {
+        session_builder = session_builder
+            .add_player(ggrs::PlayerType::Local, handle)
+            .expect("failed to add player");
+    }
+
+    let ggrs_session = session_builder
+        .start_synctest_session()
+        .expect("failed to start session");
+
+    commands.insert_resource(Session::SyncTest(ggrs_session));
+    commands.insert_resource(SessionSeed(info.seed));
+    commands.insert_resource(NumPlayers(info.num_players));
+    commands.insert_resource(info.settings);
+    commands.insert_resource(Replay {
+        seed: info.seed,
+        num_players: info.num_players,
+        settings: info.settings,
+        frames: std::mem::take(&mut lobby.streamed_inputs),
+    });
+    next_state.set(GameState::InGame);
+
+    // until we know how many frames we have inputs for
+    time.pause();
+}
+
+/// Adds the inputs streamed by the host to the match we're simulating, and
+/// paces the simulation so it never gets ahead of them
+pub fn follow_streamed_match(
+    mut commands: Commands,
+    mut lobby: ResMut<Lobby>,
+    mut socket: ResMut<MatchboxSocket>,
+    mut replay: ResMut<Replay>,
+    mut time: ResMut<Time<Virtual>>,
+    match_over: Option<Res<MatchOver>>,
+    frame: Res<FrameCount>,
+    frame_rate: Res<RollbackFrameRate>,
+    settings: Res<MatchSettings>,
+) {
+    lobby.update(&mut socket, PeerRole::Spectator, *settings);
+    let streamed = std::mem::take(&mut lobby.streamed_inputs);
+    replay.frames.extend(streamed);
+
+    let host_left = lobby
+        .match_in_progress
+        .is_some_and(|(host, _)| !lobby.peers.contains_key(&host));
+    if host_left && match_over.is_none() {
+        commands.insert_resource(MatchOver::new("The host left the match"));
+    }
+
+    // frames without inputs yet would be simulated with made up ones, so the
+    // next update may only simulate the frames we have inputs for
+    let behind = replay.frames.len().saturating_sub(frame.0 as usize);
+    if behind < 2 {
+        time.pause();
+        return;
+    }
+    let speed = if behind as f32 > CATCH_UP_SECONDS * frame_rate.0 as f32 {
+        CATCH_UP_SPEED
+    } else {
+        1.
+    };
+    // half a frame of slack, for rounding
+    let max_frames = behind as f32 - 1.5;
+    time.unpause();
+    time.set_relative_speed(speed);
+    time.set_max_delta(Duration::from_secs_f32(
+        max_frames / frame_rate.0 as f32 / speed,
+    ));
+}
@@ -0,0 +1,475 @@
+use crate::{
+    MatchmakingError,
+    args::Args,
+    bindings::BindingsUi,
+    gameplay::{MAX_PLAYERS, MatchSettings},
+    handshake::Handshake,
+    input::PlayerInput,
+    start_matchbox_socket,
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_egui::{
+    EguiContexts,
+    egui::{self, Align2, Color32, RichText},
+};
+use bevy_matchbox::prelude::*;
+use rand::{Rng, rng};
+
+/// GGRS takes ownership of this channel when the session starts
+pub const GGRS_CHANNEL: usize = 0;
+/// Reliable channel for talking to peers before the session starts
+pub const LOBBY_CHANNEL: usize = 1;
+/// Used by the [`Handshake`] session that measures the ping between players
+pub const HANDSHAKE_CHANNEL: usize = 2;
+
+const ROOM_CODE_LENGTH: usize = 5;
+/// Letters and digits that are hard to mix up when reading a code out loud
+const ROOM_CODE_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+/// Keeps input messages to late spectators well below what data channels can
+/// send in one message
+const MAX_FRAMES_PER_MESSAGE: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerRole {
+    Player,
+    Spectator,
+}
+
+/// What we know about another peer in the room
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LobbyPeer {
+    /// `None` until the peer has introduced itself
+    pub role: Option<PeerRole>,
+    pub ready: bool,
+    /// Rules the peer wants to play with, `None` until it has told us
+    pub settings: Option<MatchSettings>,
+    /// Sent by players once they've measured their ping
+    pub input_delay: Option<InputDelayProposal>,
+}
+
+/// Input delay a player wants to play with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputDelayProposal {
+    pub frames: usize,
+    /// Set with `--input-delay` instead of measured
+    pub forced: bool,
+}
+
+/// A match that had already started when we joined, as told by its host
+#[derive(Clone, Copy, Debug)]
+pub struct MatchInProgress {
+    pub seed: u64,
+    pub num_players: usize,
+    pub settings: MatchSettings,
+}
+
+/// Messages sent on the [`LOBBY_CHANNEL`]
+#[derive(Clone, Debug)]
+enum LobbyMessage {
+    Role(PeerRole),
+    Ready(bool),
+    MatchInProgress(MatchInProgress),
+    /// Confirmed inputs of every player, for the frames from `first_frame` on
+    Inputs {
+        first_frame: usize,
+        frames: Vec<Vec<PlayerInput>>,
+    },
+    InputDelay(InputDelayProposal),
+    Settings(MatchSettings),
+}
+
+fn encode_settings(settings: MatchSettings) -> [u8; 4] {
+    [
+        settings.self_damage as u8,
+        settings.max_health as u8,
+        settings.ricochet as u8,
+        settings.tick_rate as u8,
+    ]
+}
+
+fn decode_settings([self_damage, max_health, ricochet, tick_rate]: [u8; 4]) -> MatchSettings {
+    MatchSettings {
+        self_damage: self_damage != 0,
+        max_health: max_health as u32,
+        ricochet: ricochet != 0,
+        tick_rate: tick_rate as u32,
+    }
+}
+
+impl LobbyMessage {
+    fn encode(self) -> Box<[u8]> {
+        match self {
+            LobbyMessage::Role(role) => vec![0, (role == PeerRole::Spectator) as u8],
+            LobbyMessage::Ready(ready) => vec![1, ready as u8],
+            LobbyMessage::MatchInProgress(info) => [
+                &[2],
+                &info.seed.to_le_bytes()[..],
+                &[info.num_players as u8],
+                &encode_settings(info.settings),
+            ]
+            .concat(),
+            LobbyMessage::Inputs {
+                first_frame,
+                frames,
+            } => {
+                let num_players = frames.first().map_or(0, Vec::len);
+                let mut bytes = vec![3];
+                bytes.extend((first_frame as u32).to_le_bytes());
+                bytes.push(num_players as u8);
+                bytes.extend(frames.iter().flatten().flat_map(|input| input.to_bytes()));
+                bytes
+            }
+            LobbyMessage::InputDelay(proposal) => {
+                vec![4, proposal.frames as u8, proposal.forced as u8]
+            }
+            LobbyMessage::Settings(settings) => [&[5], &encode_settings(settings)[..]].concat(),
+        }
+        .into_boxed_slice()
+    }
+
+    fn decode(packet: &[u8]) -> Option<Self> {
+        match packet {
+            [0, 0] => Some(LobbyMessage::Role(PeerRole::Player)),
+            [0, 1] => Some(LobbyMessage::Role(PeerRole::Spectator)),
+            [1, ready] => Some(LobbyMessage::Ready(*ready != 0)),
+            [2, rest @ ..] => {
+                let (seed, [num_players, settings @ ..]) = rest.split_first_chunk::<8>()? else {
+                    return None;
+                };
+                let num_players = *num_players as usize;
+                let settings = decode_settings(settings.try_into().ok()?);
+                if !(2..=MAX_PLAYERS).contains(&num_players)
+                    || settings.max_health == 0
+                    || settings.tick_rate == 0
+                {
+                    return None;
+                }
+                Some(LobbyMessage::MatchInProgress(MatchInProgress {
+                    seed: u64::from_le_bytes(*seed),
+                    num_players,
+                    settings,
+                }))
+            }
+            [3, rest @ ..] => {
+                let (first_frame, [num_players, inputs @ ..]) = rest.split_first_chunk::<4>()?
+                else {
+                    return None;
+                };
+                let frame_len = *num_players as usize * PlayerInput::BYTES;
+                if frame_len == 0 || inputs.len() % frame_len != 0 {
+                    return None;
+                }
+                let frames = inputs
+                    .chunks(frame_len)
+                    .map(|frame| {
+                        frame
+                            .chunks(PlayerInput::BYTES)
+                            .map(|input| PlayerInput::from_bytes(input.try_into().unwrap()))
+                            .collect()
+                    })
+                    .collect();
+                Some(LobbyMessage::Inputs {
+                    first_frame: u32::from_le_bytes(*first_frame) as usize,
+                    frames,
+                })
+            }
+            [4, frames, forced] => Some(LobbyMessage::InputDelay(InputDelayProposal {
+                frames: *frames as usize,
+                forced: *forced != 0,
+            })),
+            [5, settings @ ..] => Some(LobbyMessage::Settings(decode_settings(
+                settings.try_into().ok()?,
+            ))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Resource, Default, Debug)]
+pub struct Lobby {
+    /// Code of the room we're in, if any
+    pub room_code: Option<String>,
+    /// Contents of the "join room" text field
+    code_input: String,
+    pub ready: bool,
+    pub peers: HashMap<PeerId, LobbyPeer>,
+    /// Set when we've joined a match that already started, along with its host
+    pub match_in_progress: Option<(PeerId, MatchInProgress)>,
+    /// Inputs the host of that match has sent us, and we haven't simulated yet
+    pub streamed_inputs: Vec<Vec<PlayerInput>>,
+    /// Frames of inputs received from the host so far
+    streamed_frames: usize,
+}
+
+impl Lobby {
+    /// Handles peers joining and leaving, and messages from peers in the room
+    pub fn update(
+        &mut self,
+        socket: &mut MatchboxSocket,
+        own_role: PeerRole,
+        own_settings: MatchSettings,
+    ) {
+        for (peer, state) in socket.update_peers() {
+            match state {
+                PeerState::Connected => {
+                    self.peers.insert(peer, default());
+
+                    // introduce ourselves to the new peer
+                    let channel = socket.channel_mut(LOBBY_CHANNEL);
+                    channel.send(LobbyMessage::Role(own_role).encode(), peer);
+                    channel.send(LobbyMessage::Settings(own_settings).encode(), peer);
+                    channel.send(LobbyMessage::Ready(self.ready).encode(), peer);
+                }
+                PeerState::Disconnected => {
+                    self.peers.remove(&peer);
+                }
+            }
+        }
+
+        for (peer, packet) in socket.channel_mut(LOBBY_CHANNEL).receive() {
+            let Some(message) = LobbyMessage::decode(&packet) else {
+                warn!("invalid lobby message from {peer}: {packet:?}");
+                continue;
+            };
+
+            let lobby_peer = self.peers.entry(peer).or_default();
+            match message {
+                LobbyMessage::Role(role) => lobby_peer.role = Some(role),
+                LobbyMessage::Ready(ready) => lobby_peer.ready = ready,
+                LobbyMessage::MatchInProgress(info) => self.match_in_progress = Some((peer, info)),
+                LobbyMessage::Inputs {
+                    first_frame,
+                    frames,
+                } => {
+                    if first_frame != self.streamed_frames {
+                        warn!(
+                            "expected inputs from frame {}, got them from frame {first_frame}",
+                            self.streamed_frames
+                        );
+                        continue;
+                    }
+                    self.streamed_frames += frames.len();
+                    self.streamed_inputs.extend(frames);
+                }
+                LobbyMessage::InputDelay(proposal) => lobby_peer.input_delay = Some(proposal),
+                LobbyMessage::Settings(settings) => lobby_peer.settings = Some(settings),
+            }
+        }
+    }
+
+    fn set_ready(&mut self, socket: &mut MatchboxSocket, ready: bool) {
+        self.ready = ready;
+
+        let channel = socket.channel_mut(LOBBY_CHANNEL);
+        for peer in self.peers.keys() {
+            channel.send(LobbyMessage::Ready(ready).encode(), *peer);
+        }
+    }
+
+    pub fn send_input_delay(&self, socket: &mut MatchboxSocket, proposal: InputDelayProposal) {
+        let channel = socket.channel_mut(LOBBY_CHANNEL);
+        for peer in self.peers.keys() {
+            channel.send(LobbyMessage::InputDelay(proposal).encode(), *peer);
+        }
+    }
+
+    /// Tells a peer that joined after the match started which match it's
+    /// watching, so it can simulate the match along with us
+    pub fn send_match_in_progress(
+        &self,
+        socket: &mut MatchboxSocket,
+        peer: PeerId,
+        info: MatchInProgress,
+    ) {
+        let channel = socket.channel_mut(LOBBY_CHANNEL);
+        channel.send(LobbyMessage::MatchInProgress(info).encode(), peer);
+    }
+
+    /// Sends a late spectator the inputs of `frames`, which start at `first_frame`
+    pub fn send_inputs(
+        &self,
+        socket: &mut MatchboxSocket,
+        peer: PeerId,
+        first_frame: usize,
+        frames: &[Vec<PlayerInput>],
+    ) {
+        let channel = socket.channel_mut(LOBBY_CHANNEL);
+        for (i, chunk) in frames.chunks(MAX_FRAMES_PER_MESSAGE).enumerate() {
+            let message = LobbyMessage::Inputs {
+                first_frame: first_frame + i * MAX_FRAMES_PER_MESSAGE,
+                frames: chunk.to_vec(),
+            };
+            channel.send(message.encode(), peer);
+        }
+    }
+
+    /// Whether `num_peers` peers (including us) are in the room, and all of them are ready
+    pub fn everyone_ready(&self, num_peers: usize) -> bool {
+        self.ready
+            && self.peers.len() + 1 >= num_peers
+            && self
+                .peers
+                .values()
+                .all(|peer| peer.ready && peer.role.is_some() && peer.settings.is_some())
+    }
+
+    /// Whether every peer wants to play with the same rules as us
+    pub fn settings_match(&self, own_settings: MatchSettings) -> bool {
+        self.peers
+            .values()
+            .all(|peer| peer.settings == Some(own_settings))
+    }
+
+    /// Ids of the peers with the given role, sorted so every peer agrees on the order
+    pub fn peers_with_role(&self, role: PeerRole) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.role == Some(role))
+            .map(|(id, _)| *id)
+            .collect();
+        peers.sort();
+        peers
+    }
+}
+
+fn random_room_code() -> String {
+    let mut rng = rng();
+    (0..ROOM_CODE_LENGTH)
+        .map(|_| ROOM_CODE_CHARS[rng.random_range(0..ROOM_CODE_CHARS.len())] as char)
+        .collect()
+}
+
+/// Room codes are case insensitive, and people tend to paste whitespace
+fn normalize_room_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn join_room(commands: &mut Commands, lobby: &mut Lobby, args: &Args, code: &str) {
+    let code = normalize_room_code(code);
+    commands.remove_resource::<MatchmakingError>();
+    start_matchbox_socket(commands, args, &code);
+    lobby.room_code = Some(code);
+}
+
+/// Skips the create/join screen if a room was given with `--room`
+pub fn join_room_from_args(mut commands: Commands, mut lobby: ResMut<Lobby>, args: Res<Args>) {
+    if let Some(room) = &args.room {
+        join_room(&mut commands, &mut lobby, &args, room);
+    }
+}
+
+pub fn lobby_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut lobby: ResMut<Lobby>,
+    mut socket: Option<ResMut<MatchboxSocket>>,
+    mut bindings_ui: ResMut<BindingsUi>,
+    handshake: Option<Res<Handshake>>,
+    error: Option<Res<MatchmakingError>>,
+    args: Res<Args>,
+) -> Result {
+    let mut room_to_join = None;
+
+    egui::Window::new("Lobby")
+        .anchor(Align2::CENTER_CENTER, (0., 0.))
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            if ui.button("Key bindings (F1)").clicked() {
+                bindings_ui.open = true;
+            }
+
+            if let Some(error) = &error {
+                ui.colored_label(
+                    Color32::DARK_RED,
+                    format!("Matchmaking failed: {}", error.0),
+                );
+            }
+
+            let (Some(room_code), Some(socket)) = (lobby.room_code.clone(), socket.as_mut()) else {
+                if ui.button("Create room").clicked() {
+                    room_to_join = Some(random_room_code());
+                }
+
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    ui.label("Room code:");
+                    ui.text_edit_singleline(&mut lobby.code_input);
+                    if ui.button("Join").clicked() && !lobby.code_input.trim().is_empty() {
+                        room_to_join = Some(lobby.code_input.clone());
+                    }
+                });
+                return;
+            };
+
+            ui.horizontal(|ui| {
+                ui.label("Room code:");
+                ui.label(RichText::new(&room_code).monospace().size(32.));
+                if ui.button("Copy").clicked() {
+                    ui.ctx().copy_text(room_code.clone());
+                }
+            });
+
+            ui.separator();
+
+            ui.label(format!(
+                "{} of {} peers joined",
+                lobby.peers.len() + 1,
+                args.num_peers()
+            ));
+
+            egui::Grid::new("lobby_peers").striped(true).show(ui, |ui| {
+                let role_name = |role: Option<PeerRole>| match role {
+                    Some(PeerRole::Player) => "player",
+                    Some(PeerRole::Spectator) => "spectator",
+                    None => "connecting",
+                };
+                let ready_name = |ready: bool| if ready { "ready" } else { "not ready" };
+
+                let own_role = if args.spectate {
+                    PeerRole::Spectator
+                } else {
+                    PeerRole::Player
+                };
+                ui.strong("You");
+                ui.label(role_name(Some(own_role)));
+                ui.label(ready_name(lobby.ready));
+                ui.end_row();
+
+                let mut peers: Vec<_> = lobby.peers.iter().collect();
+                peers.sort_by_key(|(id, _)| **id);
+                for (id, peer) in peers {
+                    // the full uuid is a bit much
+                    let id = id.0.to_string();
+                    ui.label(&id[..8]);
+                    ui.label(role_name(peer.role));
+                    ui.label(ready_name(peer.ready));
+                    ui.end_row();
+                }
+            });
+
+            ui.separator();
+
+            if handshake.is_some() {
+                ui.label("Measuring ping to the other players...");
+                return;
+            }
+
+            let mut ready = lobby.ready;
+            if ui.checkbox(&mut ready, "Ready").changed() {
+                lobby.set_ready(socket, ready);
+            }
+
+            if !lobby.everyone_ready(args.num_peers()) {
+                ui.label("The match starts when everyone is ready");
+            }
+        });
+
+    if let Some(code) = room_to_join {
+        join_room(&mut commands, &mut lobby, &args, &code);
+    }
+
+    Ok(())
+}
@@ -1,5 +1,16 @@
-use args::Args;
-use bevy::{camera::ScalingMode, prelude::*};
+// systems take their resources and queries as arguments, which clippy doesn't
+// like the look of
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+use args::{Args, InputSource};
+use bevy::{
+    camera::ScalingMode,
+    input::common_conditions::input_pressed,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+    time::common_conditions::on_timer,
+    transform::TransformSystems,
+};
 use bevy_asset_loader::prelude::*;
 use bevy_egui::{
     EguiContexts, EguiPlugin,
@@ -7,22 +18,48 @@ use bevy_egui::{
 };
 use bevy_ggrs::{ggrs::DesyncDetection, prelude::*, *};
 use bevy_matchbox::prelude::*;
-use bevy_roll_safe::prelude::*;
-use clap::Parser;
+use bevy_roll_safe::{InitialStateEntered, prelude::*};
+use bindings::*;
 use components::*;
+use desync::*;
+use fixed::{UNIT, to_world};
+use gameplay::*;
+use handshake::*;
 use input::*;
-use rand::{Rng, RngCore, SeedableRng, rng};
-use rand_xoshiro::Xoshiro256PlusPlus;
+use interpolation::*;
+use late_spectators::*;
+use lobby::*;
+use network_stats::*;
+use rand::{RngCore, rng};
+use replay::*;
+use std::time::Duration;
+use touch::*;
+use weapons::Weapon;
 
 mod args;
+mod bindings;
 mod components;
+mod desync;
+mod fixed;
+mod gameplay;
+mod handshake;
+mod headless;
 mod input;
-
-// The first generic parameter, u8, is the input type: 4-directions + fire fits
-// easily in a single byte
+mod interpolation;
+mod late_spectators;
+mod lobby;
+mod network_stats;
+mod replay;
+mod rollback;
+mod storage;
+mod touch;
+mod weapons;
+
+// The first generic parameter is the input type: 4-directions, fire, reload and
+// analog speed fit in a single byte, plus two more for the aim direction
 // The second parameter is the address type of peers: Matchbox' WebRtcSocket
 // addresses are called `PeerId`s
-type Config = bevy_ggrs::GgrsConfig<u8, PeerId>;
+type Config = bevy_ggrs::GgrsConfig<PlayerInput, PeerId>;
 
 #[derive(States, Clone, Eq, PartialEq, Debug, Hash, Default)]
 enum GameState {
@@ -32,34 +69,72 @@ enum GameState {
     InGame,
 }
 
-#[derive(States, Clone, Eq, PartialEq, Debug, Hash, Default)]
-enum RollbackState {
-    /// When the characters running and gunning
-    #[default]
-    InRound,
-    /// When one character is dead, and we're transitioning to the next round
-    RoundEnd,
+/// Peers we've lost contact with, and how long until GGRS gives up on them
+#[derive(Resource, Default, Debug)]
+struct InterruptedPeers(HashMap<PeerId, Timer>);
+
+/// Handles of players that left the current match
+#[derive(Resource, Default, Debug)]
+struct LeftPlayers(HashSet<usize>);
+
+/// Set when the match is over, e.g. because a peer left, and we're about to
+/// go back to the lobby
+#[derive(Resource, Debug)]
+struct MatchOver {
+    message: String,
+    timer: Timer,
 }
 
-#[derive(Resource, Clone, Deref, DerefMut)]
-struct RoundEndTimer(Timer);
+impl MatchOver {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            timer: Timer::from_seconds(3.0, TimerMode::Once),
+        }
+    }
+}
 
-#[derive(Resource, Default, Clone, Copy, Debug)]
-struct Scores(u32, u32);
+/// A message shown for a few seconds while the match goes on, e.g. when one of
+/// several players left
+#[derive(Resource, Debug)]
+struct Notice {
+    message: String,
+    timer: Timer,
+}
 
-impl Default for RoundEndTimer {
-    fn default() -> Self {
-        RoundEndTimer(Timer::from_seconds(1.0, TimerMode::Repeating))
+impl Notice {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            timer: Timer::from_seconds(3.0, TimerMode::Once),
+        }
     }
 }
 
-#[derive(Resource, Default, Clone, Copy, Debug, Deref, DerefMut)]
-struct SessionSeed(u64);
+/// Set when we're unable to start matchmaking, e.g. because of a malformed room url
+#[derive(Resource, Clone, Debug, Deref)]
+struct MatchmakingError(String);
 
-fn main() {
-    let args = Args::parse();
+fn main() -> AppExit {
+    let args = Args::get();
     eprintln!("{args:?}");
 
+    if let Some(dumps) = &args.diff_desync {
+        if let Err(err) = print_diff(&dumps[0], &dumps[1]) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return AppExit::Success;
+    }
+
+    if args.headless {
+        if let Err(err) = headless::run(&args) {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return AppExit::Success;
+    }
+
     App::new()
         .add_plugins((
             DefaultPlugins
@@ -74,8 +149,7 @@ fn main() {
                     ..default()
                 })
                 .set(ImagePlugin::default_nearest()),
-            GgrsPlugin::<Config>::default(),
-            RollbackSchedulePlugin::new_ggrs(),
+            GameplayPlugin,
             EguiPlugin::default(),
         ))
         .init_state::<GameState>()
@@ -84,78 +158,146 @@ fn main() {
                 .load_collection::<ImageAssets>()
                 .continue_to_state(GameState::Matchmaking),
         )
-        .init_ggrs_state::<RollbackState>()
-        .rollback_resource_with_clone::<RoundEndTimer>()
-        .rollback_resource_with_copy::<Scores>()
-        .rollback_component_with_clone::<Transform>()
-        .rollback_component_with_copy::<Bullet>()
-        .rollback_component_with_copy::<BulletReady>()
-        .rollback_component_with_copy::<Player>()
-        .rollback_component_with_copy::<Wall>()
-        .rollback_component_with_copy::<MoveDir>()
-        .rollback_component_with_copy::<DistanceTraveled>()
-        .rollback_component_with_clone::<Sprite>()
-        .checksum_component::<Transform>(checksum_transform)
         .insert_resource(args)
         .insert_resource(ClearColor(Color::srgb(0.53, 0.53, 0.53)))
-        .init_resource::<RoundEndTimer>()
-        .init_resource::<Scores>()
+        .init_resource::<DesyncHistory>()
+        .init_resource::<FrameChecksums>()
+        .init_resource::<ReplayRecorder>()
+        .init_resource::<ReplayControls>()
+        .init_resource::<Lobby>()
+        .init_resource::<InterruptedPeers>()
+        .init_resource::<LeftPlayers>()
+        .init_resource::<GamepadAssignments>()
+        .init_resource::<NetworkStatsHistory>()
+        .init_resource::<TickProgress>()
+        .insert_resource(KeyBindings::load())
+        .init_resource::<BindingsUi>()
+        .add_systems(Startup, load_replay.run_if(recorded_input_source))
+        .add_systems(OnExit(GameState::AssetLoading), setup)
         .add_systems(
             OnEnter(GameState::Matchmaking),
-            (setup, start_matchbox_socket.run_if(p2p_mode)),
+            join_room_from_args.run_if(p2p_mode),
+        )
+        .add_systems(
+            OnExit(GameState::InGame),
+            (save_replay.run_if(recording), end_match).chain(),
         )
         .add_systems(
             Update,
             (
+                (toggle_bindings_ui, bindings_ui.run_if(bindings_ui_open)).chain(),
                 (
-                    wait_for_players.run_if(p2p_mode),
+                    wait_for_players
+                        .run_if(p2p_mode)
+                        .run_if(resource_exists::<MatchboxSocket>),
+                    update_handshake.run_if(resource_exists::<Handshake>),
                     start_synctest_session.run_if(synctest_mode),
+                    start_local_session.run_if(local_mode),
+                    start_replay_session.run_if(replay_mode),
+                    lobby_ui.run_if(p2p_mode),
                 )
                     .run_if(in_state(GameState::Matchmaking)),
-                (camera_follow, update_score_ui, handle_ggrs_events)
+                (
+                    (
+                        add_player_sprites,
+                        add_bullet_sprites,
+                        add_wall_sprites,
+                        add_pickup_sprites,
+                    ),
+                    update_player_sprites.after(add_player_sprites),
+                    update_bullet_sprites,
+                    update_health_bars.after(add_player_sprites),
+                    assign_gamepads,
+                    touch_controls_ui,
+                    spectator_camera.run_if(free_camera_mode),
+                    update_spectator_ui.run_if(spectator_mode),
+                    (replay_ui, apply_replay_controls.after(replay_ui)).run_if(replay_mode),
+                    // the replay is downloaded when the match ends on wasm
+                    save_replay
+                        .run_if(recording)
+                        .run_if(|| cfg!(not(target_arch = "wasm32")))
+                        .run_if(on_timer(Duration::from_secs(10))),
+                    update_score_ui,
+                    update_ammo_ui.run_if(not(free_camera_mode)),
+                    (
+                        sample_network_stats,
+                        network_stats_ui.after(sample_network_stats),
+                    )
+                        .run_if(p2p_mode),
+                    update_stats_ui.run_if(input_pressed(KeyCode::Tab)),
+                    handle_ggrs_events,
+                    update_connection_ui.after(handle_ggrs_events),
+                    match_over_timeout.run_if(resource_exists::<MatchOver>),
+                    end_synctest.run_if(synctest_mode),
+                    serve_late_spectators.run_if(resource_exists::<LateSpectators>),
+                    follow_streamed_match.run_if(late_spectating),
+                )
                     .run_if(in_state(GameState::InGame)),
             ),
         )
-        .add_systems(ReadInputs, read_local_inputs)
         .add_systems(
-            OnEnter(RollbackState::InRound),
-            (generate_map, spawn_players.after(generate_map)),
+            ReadInputs,
+            (
+                read_local_inputs
+                    .run_if(keyboard_input_source)
+                    .run_if(not(late_spectating)),
+                random_inputs.run_if(random_input_source),
+                replay_inputs.run_if(recorded_input_source.or(late_spectating)),
+            ),
         )
         .add_systems(
-            RollbackUpdate,
+            RollbackPostUpdate,
             (
-                move_players,
-                update_player_sprites
-                    .after(move_players)
-                    // both systems operate on the `Sprite` component, but not on the same entities
-                    .ambiguous_with(resolve_wall_collisions)
-                    // both systems operate on the `Sprite` component, but not on the same entities
-                    .ambiguous_with(bullet_wall_collisions),
-                resolve_wall_collisions.after(move_players),
-                reload_bullet,
-                fire_bullets
-                    .after(move_players)
-                    .after(reload_bullet)
-                    .after(resolve_wall_collisions),
-                move_bullet.after(fire_bullets),
-                bullet_wall_collisions.after(move_bullet),
-                kill_players.after(move_bullet).after(move_players),
+                record_snapshot.run_if(p2p_mode.or(synctest_mode)),
+                record_checksum.run_if(synctest_mode),
             )
-                .run_if(in_state(RollbackState::InRound))
-                .after(bevy_roll_safe::apply_state_transition::<RollbackState>),
+                .chain()
+                .after(increment_frame_count),
+        )
+        .add_systems(
+            RollbackPostUpdate,
+            count_rollbacks
+                .run_if(p2p_mode)
+                .after(increment_frame_count),
         )
+        // the host also needs the confirmed inputs for late spectators
         .add_systems(
-            RollbackUpdate,
-            round_end_timeout
-                .run_if(in_state(RollbackState::RoundEnd))
-                .ambiguous_with(kill_players),
+            RollbackPreUpdate,
+            record_inputs.run_if(recording.or(resource_exists::<LateSpectators>)),
         )
-        .run();
+        // the camera follows players where they're drawn, not where they are
+        .add_systems(
+            PostUpdate,
+            (
+                interpolate_transforms,
+                camera_follow
+                    .run_if(not(free_camera_mode))
+                    .run_if(in_state(GameState::InGame)),
+            )
+                .chain()
+                .before(TransformSystems::Propagate),
+        )
+        .run()
 }
 
-const MAP_SIZE: i32 = 41;
 const GRID_WIDTH: f32 = 0.05;
 
+/// Visible area at the default zoom level
+const CAMERA_WIDTH: f32 = 16.;
+const CAMERA_HEIGHT: f32 = 9.;
+/// Space to keep around local players when zooming out to fit them all
+const CAMERA_MARGIN: f32 = 3.;
+
+const HEALTH_BAR_SIZE: Vec2 = Vec2::new(0.8, 0.1);
+/// Height of the health bar above the center of the player
+const HEALTH_BAR_OFFSET: f32 = 0.9;
+
+/// Draw order of gameplay entities
+const WALL_Z: f32 = 10.;
+const PICKUP_Z: f32 = 50.;
+const PLAYER_Z: f32 = 100.;
+const BULLET_Z: f32 = 200.;
+
 #[derive(AssetCollection, Resource)]
 struct ImageAssets {
     #[asset(path = "bullet.png")]
@@ -166,15 +308,59 @@ struct ImageAssets {
     player_2: Handle<Image>,
 }
 
+/// 8 directional animations per player, up to 6 frames each
+#[derive(Resource, Deref)]
+struct PlayerAtlasLayout(Handle<TextureAtlasLayout>);
+
+/// The part of a player's health bar that shrinks as they take damage
+#[derive(Component)]
+struct HealthBarFill;
+
 fn synctest_mode(args: Res<Args>) -> bool {
     args.synctest
 }
 
 fn p2p_mode(args: Res<Args>) -> bool {
-    !args.synctest
+    !args.synctest && !args.local && args.replay.is_none()
 }
 
-fn setup(mut commands: Commands) {
+fn local_mode(args: Res<Args>) -> bool {
+    args.local
+}
+
+fn spectator_mode(args: Res<Args>) -> bool {
+    args.spectate
+}
+
+fn keyboard_input_source(args: Res<Args>) -> bool {
+    args.input_source() == InputSource::Keyboard
+}
+
+fn random_input_source(args: Res<Args>) -> bool {
+    args.input_source() == InputSource::Random
+}
+
+fn recorded_input_source(args: Res<Args>) -> bool {
+    matches!(args.input_source(), InputSource::File(_))
+}
+
+fn replay_mode(args: Res<Args>) -> bool {
+    args.replay.is_some()
+}
+
+/// The camera doesn't follow any player when there are no players to follow
+fn free_camera_mode(args: Res<Args>) -> bool {
+    args.spectate || args.replay.is_some()
+}
+
+fn recording(args: Res<Args>) -> bool {
+    args.record.is_some() && args.replay.is_none()
+}
+
+fn setup(mut commands: Commands, mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>) {
+    let layout = TextureAtlasLayout::from_grid(UVec2::splat(22), 6, 8, None, None);
+    commands.insert_resource(PlayerAtlasLayout(texture_atlas_layouts.add(layout)));
+
     // Horizontal lines
     for i in 0..=MAP_SIZE {
         commands.spawn((
@@ -203,185 +389,356 @@ fn setup(mut commands: Commands) {
         Camera2d,
         Projection::Orthographic(OrthographicProjection {
             scaling_mode: ScalingMode::AutoMax {
-                max_width: 16.0,
-                max_height: 9.0,
+                max_width: CAMERA_WIDTH,
+                max_height: CAMERA_HEIGHT,
             },
             ..OrthographicProjection::default_2d()
         }),
     ));
 }
 
-fn generate_map(
+// The gameplay doesn't know about assets, so sprites are added to its entities
+// here. Entities respawned by a rollback lose their sprite, so this isn't
+// limited to newly spawned entities.
+
+fn add_player_sprites(
     mut commands: Commands,
-    walls: Query<Entity, With<Wall>>,
-    scores: Res<Scores>,
-    session_seed: Res<SessionSeed>,
+    mut players: Query<(Entity, &Player, &mut Transform), Without<Sprite>>,
+    images: Res<ImageAssets>,
+    layout: Res<PlayerAtlasLayout>,
 ) {
-    // despawn walls from previous round (if any)
-    for wall in &walls {
-        commands.entity(wall).despawn();
+    for (entity, player, mut transform) in &mut players {
+        transform.translation.z = PLAYER_Z;
+
+        // there are only two player sprites, so players 3-8 get tinted versions of them
+        let image = if player.handle % 2 == 0 {
+            images.player_1.clone()
+        } else {
+            images.player_2.clone()
+        };
+
+        commands
+            .entity(entity)
+            .insert(Sprite {
+                image,
+                color: player_tint(player.handle),
+                texture_atlas: Some(TextureAtlas {
+                    layout: layout.clone(),
+                    index: 0,
+                }),
+                custom_size: Some(Vec2::splat(1.4)),
+                ..default()
+            })
+            .with_children(|health_bar| {
+                health_bar.spawn((
+                    Transform::from_xyz(0., HEALTH_BAR_OFFSET, 1.),
+                    Sprite {
+                        color: Color::srgb(0.2, 0.2, 0.2),
+                        custom_size: Some(HEALTH_BAR_SIZE),
+                        ..default()
+                    },
+                ));
+                health_bar.spawn((
+                    HealthBarFill,
+                    Transform::from_xyz(0., HEALTH_BAR_OFFSET, 2.),
+                    Sprite {
+                        color: Color::srgb(0.2, 0.8, 0.2),
+                        custom_size: Some(HEALTH_BAR_SIZE),
+                        ..default()
+                    },
+                ));
+            });
     }
+}
 
-    let mut rng = Xoshiro256PlusPlus::seed_from_u64((scores.0 + scores.1) as u64 ^ **session_seed);
-
-    for _ in 0..20 {
-        let max_box_size = MAP_SIZE / 4;
-        let width = rng.random_range(1..max_box_size);
-        let height = rng.random_range(1..max_box_size);
-
-        let cell_x = rng.random_range(0..=(MAP_SIZE - width));
-        let cell_y = rng.random_range(0..=(MAP_SIZE - height));
-
-        let size = Vec2::new(width as f32, height as f32);
-
-        commands.spawn((
-            Wall,
-            Transform::from_translation(Vec3::new(
-                cell_x as f32 + size.x / 2. - MAP_SIZE as f32 / 2.,
-                cell_y as f32 + size.y / 2. - MAP_SIZE as f32 / 2.,
-                10.,
-            )),
-            Sprite {
-                color: Color::srgb(0.27, 0.27, 0.27),
-                custom_size: Some(size),
-                ..default()
-            },
-        ));
+fn update_health_bars(
+    players: Query<(&Health, &Children), With<Player>>,
+    mut fills: Query<(&mut Sprite, &mut Transform), With<HealthBarFill>>,
+) {
+    for (health, children) in &players {
+        let width = HEALTH_BAR_SIZE.x * health.fraction();
+        let mut fills = fills.iter_many_mut(children);
+        while let Some((mut sprite, mut transform)) = fills.fetch_next() {
+            sprite.custom_size = Some(Vec2::new(width, HEALTH_BAR_SIZE.y));
+            // shrink towards the left
+            transform.translation.x = (width - HEALTH_BAR_SIZE.x) / 2.;
+        }
     }
 }
 
-fn spawn_players(
+fn add_bullet_sprites(
     mut commands: Commands,
-    players: Query<Entity, With<Player>>,
-    bullets: Query<Entity, With<Bullet>>,
-    scores: Res<Scores>,
-    session_seed: Res<SessionSeed>,
+    mut bullets: Query<(Entity, &Bullet, &mut Transform), Without<Sprite>>,
     images: Res<ImageAssets>,
-    mut texture_atlas_layouts: ResMut<Assets<TextureAtlasLayout>>,
 ) {
-    info!("Spawning players");
+    for (entity, bullet, mut transform) in &mut bullets {
+        transform.translation.z = BULLET_Z;
+        commands.entity(entity).insert(Sprite {
+            image: images.bullet.clone(),
+            color: weapon_color(bullet.weapon),
+            custom_size: Some(to_world(bullet.weapon.stats().bullet_size)),
+            ..default()
+        });
+    }
+}
 
-    for player in &players {
-        commands.entity(player).despawn();
+/// Points bullets where they're going, which changes when they ricochet
+fn update_bullet_sprites(mut bullets: Query<(&MoveDir, &mut Transform), With<Bullet>>) {
+    for (move_dir, mut transform) in &mut bullets {
+        let direction = move_dir.0.as_vec2().normalize_or(Vec2::X);
+        transform.rotation = Quat::from_rotation_arc_2d(Vec2::X, direction);
     }
+}
 
-    for bullet in &bullets {
-        commands.entity(bullet).despawn();
+fn add_pickup_sprites(
+    mut commands: Commands,
+    mut pickups: Query<(Entity, &WeaponPickup, &mut Transform), Without<Sprite>>,
+) {
+    for (entity, pickup, mut transform) in &mut pickups {
+        transform.translation.z = PICKUP_Z;
+        commands.entity(entity).insert(Sprite {
+            color: weapon_color(pickup.weapon),
+            custom_size: Some(to_world(IVec2::splat(PICKUP_SIZE))),
+            ..default()
+        });
     }
+}
 
-    let mut rng = Xoshiro256PlusPlus::seed_from_u64((scores.0 + scores.1) as u64 ^ **session_seed);
-    let half = MAP_SIZE as f32 / 2.;
-    let p1_pos = Vec2::new(rng.random_range(-half..half), rng.random_range(-half..half));
-    let p2_pos = Vec2::new(rng.random_range(-half..half), rng.random_range(-half..half));
+fn add_wall_sprites(
+    mut commands: Commands,
+    mut walls: Query<(Entity, &Wall, &mut Transform), Without<Sprite>>,
+) {
+    for (entity, wall, mut transform) in &mut walls {
+        transform.translation.z = WALL_Z;
+        commands.entity(entity).insert(Sprite {
+            color: Color::srgb(0.27, 0.27, 0.27),
+            custom_size: Some(wall.size.as_vec2()),
+            ..default()
+        });
+    }
+}
 
-    // 8 directional animations per player, up to 6 frames each
-    let layout = TextureAtlasLayout::from_grid(UVec2::splat(22), 6, 8, None, None);
-    let layout = texture_atlas_layouts.add(layout.clone());
-
-    // Player 1
-    commands
-        .spawn((
-            Player { handle: 0 },
-            Transform::from_translation(p1_pos.extend(100.)),
-            BulletReady(true),
-            MoveDir(-Vec2::X),
-            Sprite {
-                image: images.player_1.clone(),
-                texture_atlas: Some(TextureAtlas {
-                    layout: layout.clone(),
-                    index: 0,
-                }),
-                custom_size: Some(Vec2::splat(1.4)),
-                ..default()
-            },
-        ))
-        .add_rollback();
-
-    // Player 2
-    commands
-        .spawn((
-            Player { handle: 1 },
-            Transform::from_translation(p2_pos.extend(100.)),
-            BulletReady(true),
-            MoveDir(-Vec2::X),
-            Sprite {
-                image: images.player_2.clone(),
-                texture_atlas: Some(TextureAtlas {
-                    layout: layout.clone(),
-                    index: 0,
-                }),
-                custom_size: Some(Vec2::splat(1.4)),
-                ..default()
-            },
-        ))
-        .add_rollback();
+fn weapon_color(weapon: Weapon) -> Color {
+    match weapon {
+        Weapon::Pistol => Color::WHITE,
+        Weapon::Shotgun => Color::srgb(1.0, 0.6, 0.2),
+        Weapon::Rifle => Color::srgb(0.3, 0.6, 1.0),
+        Weapon::Rocket => Color::srgb(1.0, 0.2, 0.2),
+    }
 }
 
-fn start_matchbox_socket(mut commands: Commands) {
-    let room_url = "ws://127.0.0.1:3536/extreme_bevy?next=2";
+fn player_tint(handle: usize) -> Color {
+    match handle / 2 {
+        0 => Color::WHITE,
+        1 => Color::srgb(1.0, 0.5, 0.5),
+        2 => Color::srgb(0.5, 1.0, 0.5),
+        _ => Color::srgb(0.5, 0.5, 1.0),
+    }
+}
+
+fn start_matchbox_socket(commands: &mut Commands, args: &Args, room: &str) {
+    let room_url = match args.room_url(room) {
+        Ok(room_url) => room_url,
+        Err(err) => {
+            error!("failed to start matchmaking: {err}");
+            commands.insert_resource(MatchmakingError(err));
+            return;
+        }
+    };
     info!("connecting to matchbox server: {room_url}");
-    commands.insert_resource(MatchboxSocket::new_unreliable(room_url));
+    let socket = WebRtcSocketBuilder::new(room_url.as_str())
+        .add_unreliable_channel() // GGRS_CHANNEL
+        .add_reliable_channel() // LOBBY_CHANNEL
+        .add_unreliable_channel(); // HANDSHAKE_CHANNEL
+    commands.insert_resource(MatchboxSocket::from(socket));
 }
 
 fn wait_for_players(
     mut commands: Commands,
     mut socket: ResMut<MatchboxSocket>,
     mut next_state: ResMut<NextState<GameState>>,
+    mut lobby: ResMut<Lobby>,
+    mut time: ResMut<Time<Virtual>>,
     args: Res<Args>,
 ) {
-    if socket.get_channel(0).is_err() {
+    if socket.get_channel(GGRS_CHANNEL).is_err() || socket.get_channel(HANDSHAKE_CHANNEL).is_err() {
         return; // we've already started
     }
 
-    // Check for new connections
-    socket.update_peers();
-    let players = socket.players();
+    let own_role = if args.spectate {
+        PeerRole::Spectator
+    } else {
+        PeerRole::Player
+    };
+
+    let settings = args.match_settings();
+    lobby.update(&mut socket, own_role, settings);
+
+    // the host tells peers that join after the match has started about it
+    if lobby.match_in_progress.is_some() {
+        if own_role == PeerRole::Player {
+            let err =
+                "the match in this room has already started, join with --spectate to watch it"
+                    .to_string();
+            error_once!("{err}");
+            commands.insert_resource(MatchmakingError(err));
+            return;
+        }
+        start_late_spectating(&mut commands, &mut lobby, &mut next_state, &mut time);
+        return;
+    }
+
+    if !lobby.everyone_ready(args.num_peers()) {
+        return;
+    }
+
+    let own_id = socket.id().expect("no peer id assigned");
 
-    let num_players = 2;
-    if players.len() < num_players {
-        return; // wait for more players
+    let mut player_ids = lobby.peers_with_role(PeerRole::Player);
+    if own_role == PeerRole::Player {
+        player_ids.push(own_id);
+        player_ids.sort();
+    }
+    let spectator_ids = lobby.peers_with_role(PeerRole::Spectator);
+
+    let num_players = args.players;
+    if player_ids.len() != num_players {
+        let err = format!(
+            "expected {num_players} players, but {} peers want to play. Does everyone use the same --players and --spectators?",
+            player_ids.len()
+        );
+        error_once!("{err}");
+        commands.insert_resource(MatchmakingError(err));
+        return;
+    }
+
+    // everyone simulates the match, so everyone has to play by the same rules
+    if !lobby.settings_match(settings) {
+        let err =
+            "peers want to play with different rules. Does everyone use the same --self-damage, --max-health, --ricochet and --tick-rate?"
+                .to_string();
+        error_once!("{err}");
+        commands.insert_resource(MatchmakingError(err));
+        return;
     }
 
-    info!("All peers have joined, going in-game");
+    info!("All peers are ready, going in-game");
 
     // determine the seed
-    let id = socket.id().expect("no peer id assigned").0.as_u64_pair();
+    let id = own_id.0.as_u64_pair();
     let mut seed = id.0 ^ id.1;
-    for peer in socket.connected_peers() {
+    for peer in lobby.peers.keys() {
         let peer_id = peer.0.as_u64_pair();
         seed ^= peer_id.0 ^ peer_id.1;
     }
     commands.insert_resource(SessionSeed(seed));
+    commands.insert_resource(NumPlayers(num_players));
+    commands.insert_resource(settings);
+
+    if own_role == PeerRole::Spectator {
+        // the player with the lowest id is the host, and forwards inputs to spectators
+        let host = player_ids[0];
+        info!("Spectating match hosted by {host}");
+
+        // move the channel out of the socket (required because GGRS takes ownership of it)
+        let channel = socket.take_channel(GGRS_CHANNEL).unwrap();
+        let ggrs_session = ggrs::SessionBuilder::<Config>::new()
+            .with_num_players(num_players)
+            .with_fps(settings.tick_rate as usize)
+            .expect("invalid tick rate")
+            .start_spectator_session(host, channel);
+
+        commands.insert_resource(bevy_ggrs::Session::Spectator(ggrs_session));
+        next_state.set(GameState::InGame);
+        return;
+    }
+
+    // input delay can't be changed once the session has started, so players
+    // measure their ping to each other first
+    start_handshake(
+        &mut commands,
+        &mut socket,
+        own_id,
+        player_ids,
+        spectator_ids,
+    );
+}
+
+fn start_p2p_session(
+    commands: &mut Commands,
+    socket: &mut MatchboxSocket,
+    own_id: PeerId,
+    player_ids: Vec<PeerId>,
+    spectator_ids: Vec<PeerId>,
+    input_delay: usize,
+    tick_rate: u32,
+) {
+    let num_players = player_ids.len();
+
+    // the player with the lowest id is the host, and forwards inputs to spectators
+    let host = player_ids[0];
+
+    // move the channel out of the socket (required because GGRS takes ownership of it)
+    let channel = socket.take_channel(GGRS_CHANNEL).unwrap();
 
     // create a GGRS P2P session
     let mut session_builder = ggrs::SessionBuilder::<Config>::new()
         .with_num_players(num_players)
         .with_desync_detection_mode(DesyncDetection::On { interval: 1 })
-        .with_input_delay(args.input_delay);
-
-    for (i, player) in players.into_iter().enumerate() {
+        .with_input_delay(input_delay)
+        .with_fps(tick_rate as usize)
+        .expect("invalid tick rate");
+
+    for (handle, peer) in player_ids.into_iter().enumerate() {
+        let player = if peer == own_id {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(peer)
+        };
         session_builder = session_builder
-            .add_player(player, i)
+            .add_player(player, handle)
             .expect("failed to add player");
     }
 
-    // move the channel out of the socket (required because GGRS takes ownership of it)
-    let socket = socket.take_channel(0).unwrap();
+    if own_id == host {
+        // spectator handles start after the player handles
+        for (i, peer) in spectator_ids.into_iter().enumerate() {
+            session_builder = session_builder
+                .add_player(PlayerType::Spectator(peer), num_players + i)
+                .expect("failed to add spectator");
+        }
+        commands.insert_resource(LateSpectators::default());
+    }
 
     // start the GGRS session
     let ggrs_session = session_builder
-        .start_p2p_session(socket)
+        .start_p2p_session(channel)
         .expect("failed to start session");
 
     commands.insert_resource(bevy_ggrs::Session::P2P(ggrs_session));
-    next_state.set(GameState::InGame);
 }
 
-fn start_synctest_session(mut commands: Commands, mut next_state: ResMut<NextState<GameState>>) {
+fn start_synctest_session(
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<GameState>>,
+    args: Res<Args>,
+    replay: Option<Res<Replay>>,
+) {
     info!("Starting synctest session");
-    let num_players = 2;
 
-    let mut session_builder = ggrs::SessionBuilder::<Config>::new().with_num_players(num_players);
+    // recorded inputs only make sense in the match they were recorded in
+    let (num_players, seed, settings) = match &replay {
+        Some(replay) => (replay.num_players, replay.seed, replay.settings),
+        None => (
+            args.players,
+            args.seed.unwrap_or_else(|| rng().next_u64()),
+            args.match_settings(),
+        ),
+    };
+
+    let mut session_builder = ggrs::SessionBuilder::<Config>::new()
+        .with_num_players(num_players)
+        .with_check_distance(args.check_distance);
 
     for i in 0..num_players {
         session_builder = session_builder
@@ -394,265 +751,276 @@ fn start_synctest_session(mut commands: Commands, mut next_state: ResMut<NextSta
         .expect("failed to start session");
 
     commands.insert_resource(bevy_ggrs::Session::SyncTest(ggrs_session));
-    commands.insert_resource(SessionSeed(rng().next_u64()));
+    commands.insert_resource(SessionSeed(seed));
+    commands.insert_resource(NumPlayers(num_players));
+    commands.insert_resource(settings);
     next_state.set(GameState::InGame);
 }
 
-fn handle_ggrs_events(mut session: ResMut<Session<Config>>) {
-    if let Session::P2P(s) = session.as_mut() {
-        for event in s.events() {
-            match event {
-                GgrsEvent::Disconnected { .. } | GgrsEvent::NetworkInterrupted { .. } => {
-                    warn!("GGRS event: {event:?}")
-                }
-                GgrsEvent::DesyncDetected {
-                    local_checksum,
-                    remote_checksum,
-                    frame,
-                    ..
-                } => {
-                    error!(
-                        "Desync on frame {frame}. Local checksum: {local_checksum:X}, remote checksum: {remote_checksum:X}"
-                    );
-                }
-                _ => info!("GGRS event: {event:?}"),
-            }
-        }
-    }
-}
-
-fn move_players(
-    mut players: Query<(&mut Transform, &mut MoveDir, &mut DistanceTraveled, &Player)>,
-    inputs: Res<PlayerInputs<Config>>,
-    time: Res<Time>,
+fn start_local_session(
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<GameState>>,
+    args: Res<Args>,
 ) {
-    for (mut transform, mut move_direction, mut distance, player) in &mut players {
-        let (input, _) = inputs[player.handle];
-
-        let direction = direction(input);
-
-        if direction == Vec2::ZERO {
-            continue;
-        }
-
-        move_direction.0 = direction;
-
-        let move_speed = 6.;
-        let move_delta = direction * move_speed * time.delta_secs();
+    info!("Starting local session");
+    let num_players = args.players;
 
-        let old_pos = transform.translation.xy();
-        let limit = Vec2::splat(MAP_SIZE as f32 / 2. - 0.5);
-        let new_pos = (old_pos + move_delta).clamp(-limit, limit);
-
-        transform.translation.x = new_pos.x;
-        transform.translation.y = new_pos.y;
+    // a synctest session with check distance 0 never rolls back, it just
+    // advances with the inputs we give it
+    let mut session_builder = ggrs::SessionBuilder::<Config>::new()
+        .with_num_players(num_players)
+        .with_check_distance(0);
 
-        distance.0 += move_delta.length();
+    for i in 0..num_players {
+        session_builder = session_builder
+            .add_player(PlayerType::Local, i)
+            .expect("failed to add player");
     }
-}
 
-fn resolve_wall_collisions(
-    mut players: Query<&mut Transform, With<Player>>,
-    walls: Query<(&Transform, &Sprite), (With<Wall>, Without<Player>)>,
-) {
-    for mut player_transform in &mut players {
-        for (wall_transform, wall_sprite) in &walls {
-            let wall_size = wall_sprite.custom_size.expect("wall doesn't have a size");
-            let wall_pos = wall_transform.translation.xy();
-            let player_pos = player_transform.translation.xy();
-
-            let wall_to_player = player_pos - wall_pos;
-            // exploit the symmetry of the problem,
-            // treat things as if they are in the first quadrant
-            let wall_to_player_abs = wall_to_player.abs();
-            let wall_corner_to_player_center = wall_to_player_abs - wall_size / 2.;
-
-            let corner_to_corner = wall_corner_to_player_center - Vec2::splat(PLAYER_RADIUS);
-
-            if corner_to_corner.x > 0. || corner_to_corner.y > 0. {
-                // no collision
-                continue;
-            }
+    let ggrs_session = session_builder
+        .start_synctest_session()
+        .expect("failed to start session");
 
-            if corner_to_corner.x > corner_to_corner.y {
-                // least overlap on x axis
-                player_transform.translation.x -= wall_to_player.x.signum() * corner_to_corner.x;
-            } else {
-                // least overlap on y axis
-                player_transform.translation.y -= wall_to_player.y.signum() * corner_to_corner.y;
-            }
-        }
-    }
+    commands.insert_resource(bevy_ggrs::Session::SyncTest(ggrs_session));
+    commands.insert_resource(SessionSeed(rng().next_u64()));
+    commands.insert_resource(NumPlayers(num_players));
+    commands.insert_resource(args.match_settings());
+    next_state.set(GameState::InGame);
 }
 
-fn reload_bullet(
-    inputs: Res<PlayerInputs<Config>>,
-    mut players: Query<(&mut BulletReady, &Player)>,
+/// Exits after `--frames` frames, or on the first mismatch if there's a frame
+/// limit, so synctest can run unattended
+fn end_synctest(
+    frame: Res<FrameCount>,
+    checksums: Res<FrameChecksums>,
+    args: Res<Args>,
+    mut exit: MessageWriter<AppExit>,
 ) {
-    for (mut can_fire, player) in players.iter_mut() {
-        let (input, _) = inputs[player.handle];
-        if !fire(input) {
-            can_fire.0 = true;
-        }
+    let Some(frames) = args.frames else {
+        return;
+    };
+
+    if let Some(mismatched_frame) = checksums.mismatched_frames.first() {
+        error!("Synctest failed, frame {mismatched_frame} changed when re-simulated");
+        exit.write(AppExit::from_code(1));
+    } else if frame.0 >= frames {
+        info!("Synctest passed, simulated {} frames", frame.0);
+        exit.write(AppExit::Success);
     }
 }
 
-fn fire_bullets(
+fn handle_ggrs_events(
     mut commands: Commands,
-    inputs: Res<PlayerInputs<Config>>,
-    images: Res<ImageAssets>,
-    mut players: Query<(&Transform, &Player, &mut BulletReady, &MoveDir)>,
+    mut session: ResMut<Session<Config>>,
+    mut interrupted_peers: ResMut<InterruptedPeers>,
+    mut left_players: ResMut<LeftPlayers>,
+    mut desync_history: ResMut<DesyncHistory>,
 ) {
-    for (transform, player, mut bullet_ready, move_dir) in &mut players {
-        let (input, _) = inputs[player.handle];
-        if fire(input) && bullet_ready.0 {
-            let player_pos = transform.translation.xy();
-            let muzzle_offset = match move_dir.octant() {
-                0 => Vec2::new(0.5, 0.0),    // right
-                1 => Vec2::new(0.5, 0.25),   // up-right
-                2 => Vec2::new(0.25, 0.5),   // up
-                3 => Vec2::new(-0.4, 0.3),   // up-left
-                4 => Vec2::new(-0.5, 0.0),   // left
-                5 => Vec2::new(-0.4, -0.25), // down-left
-                6 => Vec2::new(-0.25, -0.5), // down
-                7 => Vec2::new(0.25, -0.25), // down-right
-                _ => unreachable!(),
-            };
-            let pos = player_pos + muzzle_offset;
-            commands
-                .spawn((
-                    Bullet,
-                    Transform::from_translation(pos.extend(200.))
-                        .with_rotation(Quat::from_rotation_arc_2d(Vec2::X, move_dir.0)),
-                    *move_dir,
-                    Sprite {
-                        image: images.bullet.clone(),
-                        custom_size: Some(Vec2::new(0.3, 0.1)),
-                        ..default()
-                    },
-                ))
-                .add_rollback();
-            bullet_ready.0 = false;
+    let events: Vec<_> = match session.as_mut() {
+        Session::P2P(s) => s.events().collect(),
+        Session::Spectator(s) => s.events().collect(),
+        Session::SyncTest(_) => return,
+    };
+
+    for event in events {
+        match event {
+            GgrsEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                warn!("GGRS event: {event:?}");
+                let timeout = Duration::from_millis(disconnect_timeout as u64);
+                interrupted_peers
+                    .0
+                    .insert(addr, Timer::new(timeout, TimerMode::Once));
+            }
+            GgrsEvent::NetworkResumed { addr } => {
+                info!("GGRS event: {event:?}");
+                interrupted_peers.0.remove(&addr);
+            }
+            GgrsEvent::Disconnected { addr } => {
+                warn!("GGRS event: {event:?}");
+                interrupted_peers.0.remove(&addr);
+
+                match session.as_ref() {
+                    Session::P2P(s) => {
+                        // spectators coming and going doesn't affect the match
+                        let num_players = s.num_players();
+                        let handles = s.handles_by_address(addr);
+                        let was_player = handles.iter().any(|handle| *handle < num_players);
+                        left_players
+                            .0
+                            .extend(handles.into_iter().filter(|handle| *handle < num_players));
+                        // the match goes on as long as there's someone to play against
+                        let players_left = num_players - left_players.0.len();
+                        match (was_player, players_left) {
+                            (false, _) => {}
+                            (true, 0 | 1) => commands.insert_resource(MatchOver::new(
+                                "A player left the match. You win by forfeit!",
+                            )),
+                            (true, _) => {
+                                commands.insert_resource(Notice::new("A player left the match"))
+                            }
+                        }
+                    }
+                    _ => commands.insert_resource(MatchOver::new("The host left the match")),
+                }
+            }
+            GgrsEvent::DesyncDetected {
+                local_checksum,
+                remote_checksum,
+                frame,
+                ..
+            } => {
+                error!(
+                    "Desync on frame {frame}. Local checksum: {local_checksum:X}, remote checksum: {remote_checksum:X}"
+                );
+                dump_desync(&mut desync_history, frame, local_checksum, remote_checksum);
+            }
+            _ => info!("GGRS event: {event:?}"),
         }
     }
 }
 
-fn move_bullet(mut bullets: Query<(&mut Transform, &MoveDir), With<Bullet>>, time: Res<Time>) {
-    for (mut transform, dir) in &mut bullets {
-        let speed = 20.;
-        let delta = dir.0 * speed * time.delta_secs();
-        transform.translation += delta.extend(0.);
-    }
-}
-
-fn bullet_wall_collisions(
-    mut commands: Commands,
-    bullets: Query<(Entity, &Transform), With<Bullet>>,
-    walls: Query<(&Transform, &Sprite), (With<Wall>, Without<Bullet>)>,
+fn match_over_timeout(
+    mut match_over: ResMut<MatchOver>,
+    mut next_state: ResMut<NextState<GameState>>,
+    time: Res<Time<Real>>,
 ) {
-    let map_limit = MAP_SIZE as f32 / 2.;
-
-    for (bullet_entity, bullet_transform) in &bullets {
-        let bullet_pos = bullet_transform.translation.xy();
+    match_over.timer.tick(time.delta());
 
-        if bullet_pos.x.abs() > map_limit || bullet_pos.y.abs() > map_limit {
-            commands.entity(bullet_entity).despawn();
-            continue;
-        }
-
-        for (wall_transform, wall_sprite) in &walls {
-            let wall_size = wall_sprite.custom_size.expect("wall doesn't have a size");
-            let wall_pos = wall_transform.translation.xy();
-            let center_to_center = wall_pos - bullet_pos;
-            // exploit symmetry
-            let center_to_center = center_to_center.abs();
-            let corner_to_center = center_to_center - wall_size / 2.;
-            if corner_to_center.x < 0. && corner_to_center.y < 0. {
-                // we're inside a wall
-                commands.entity(bullet_entity).despawn();
-                break;
-            }
-        }
+    if match_over.timer.just_finished() {
+        info!("Match over, going back to matchmaking");
+        next_state.set(GameState::Matchmaking);
     }
 }
 
-const PLAYER_WIDTH: f32 = 0.5;
-const PLAYER_HEIGHT: f32 = 1.0;
-const PLAYER_RADIUS: f32 = 0.5;
-const BULLET_RADIUS: f32 = 0.025;
-
-fn kill_players(
+/// Tears down everything from the previous match, so a new one can be started
+/// without reloading the page
+fn end_match(
     mut commands: Commands,
-    players: Query<(Entity, &Transform, &Player), Without<Bullet>>,
-    bullets: Query<&Transform, With<Bullet>>,
-    mut next_state: ResMut<NextState<RollbackState>>,
-    mut scores: ResMut<Scores>,
+    mut next_rollback_state: ResMut<NextState<RollbackState>>,
+    mut time: ResMut<Time<Virtual>>,
+    lobby: Res<Lobby>,
+    rollback_entities: Query<Entity, Or<(With<Rollback>, With<Wall>)>>,
 ) {
-    for (player_entity, player_transform, player) in &players {
-        for bullet_transform in &bullets {
-            let player_pos = player_transform.translation.xy();
-            let bullet_pos = bullet_transform.translation.xy();
-
-            // distance between player center and bullet center on each axis, individually
-            let manhattan_distance = (player_pos - bullet_pos).abs();
-
-            if manhattan_distance.x < PLAYER_WIDTH / 2. + BULLET_RADIUS
-                && manhattan_distance.y < PLAYER_HEIGHT / 2. + BULLET_RADIUS
-            {
-                commands.entity(player_entity).despawn();
-                next_state.set(RollbackState::RoundEnd);
-
-                if player.handle == 0 {
-                    scores.1 += 1;
-                } else {
-                    scores.0 += 1;
-                }
-                info!("player died: {scores:?}")
-            }
-        }
+    for entity in &rollback_entities {
+        commands.entity(entity).despawn();
+    }
+
+    commands.remove_resource::<Session<Config>>();
+    commands.remove_resource::<MatchboxSocket>();
+    commands.remove_resource::<MatchOver>();
+    commands.remove_resource::<Notice>();
+    commands.remove_resource::<LateSpectators>();
+    if lobby.match_in_progress.is_some() {
+        commands.remove_resource::<Replay>();
+        time.unpause();
+        time.set_relative_speed(1.);
+        time.set_max_delta(Time::<Virtual>::default().max_delta());
     }
+    commands.insert_resource(Lobby::default());
+    commands.insert_resource(InterruptedPeers::default());
+    commands.insert_resource(LeftPlayers::default());
+
+    commands.insert_resource(Scores::default());
+    commands.insert_resource(MatchStats::default());
+    commands.insert_resource(RoundEndTimer::default());
+    commands.insert_resource(FrameCount::default());
+    commands.insert_resource(DesyncHistory::default());
+    commands.insert_resource(FrameChecksums::default());
+    commands.insert_resource(ReplayRecorder::default());
+    commands.insert_resource(NetworkStatsHistory::default());
+
+    // have the next session enter its first state again, like the first
+    // session did, and make sure that's a new round so the map and players are
+    // spawned again
+    commands.insert_resource(InitialStateEntered::<RollbackState>::default());
+    next_rollback_state.set(RollbackState::InRound);
 }
 
+/// Follows the local player, or keeps all local players in view when several
+/// of them share the screen
 fn camera_follow(
     local_players: Res<LocalPlayers>,
     players: Query<(&Player, &Transform)>,
-    mut cameras: Query<&mut Transform, (With<Camera>, Without<Player>)>,
+    mut cameras: Query<(&mut Transform, &mut Projection), (With<Camera>, Without<Player>)>,
 ) {
-    for (player, player_transform) in &players {
-        // only follow the local player
-        if !local_players.0.contains(&player.handle) {
-            continue;
-        }
+    let positions: Vec<Vec2> = players
+        .iter()
+        .filter(|(player, _)| local_players.0.contains(&player.handle))
+        .map(|(_, transform)| transform.translation.xy())
+        .collect();
+
+    if positions.is_empty() {
+        return;
+    }
+
+    let min = positions.iter().fold(Vec2::MAX, |min, pos| min.min(*pos));
+    let max = positions.iter().fold(Vec2::MIN, |max, pos| max.max(*pos));
+    let center = (min + max) / 2.;
 
-        let pos = player_transform.translation;
+    // zoom out when players get too far apart, but never zoom in
+    let size = max - min + Vec2::splat(CAMERA_MARGIN * 2.);
+    let scale = (size.x / CAMERA_WIDTH).max(size.y / CAMERA_HEIGHT).max(1.);
 
-        for mut transform in &mut cameras {
-            transform.translation.x = pos.x;
-            transform.translation.y = pos.y;
+    for (mut transform, mut projection) in &mut cameras {
+        transform.translation.x = center.x;
+        transform.translation.y = center.y;
+
+        if let Projection::Orthographic(orthographic) = projection.as_mut() {
+            orthographic.scale = scale;
         }
     }
 }
 
-fn round_end_timeout(
-    mut timer: ResMut<RoundEndTimer>,
-    mut state: ResMut<NextState<RollbackState>>,
+/// Lets spectators pan around the map freely
+fn spectator_camera(
+    keys: Res<ButtonInput<KeyCode>>,
     time: Res<Time>,
+    mut cameras: Query<&mut Transform, With<Camera>>,
 ) {
-    timer.tick(time.delta());
+    let mut direction = Vec2::ZERO;
+    if keys.any_pressed([KeyCode::ArrowUp, KeyCode::KeyW]) {
+        direction.y += 1.;
+    }
+    if keys.any_pressed([KeyCode::ArrowDown, KeyCode::KeyS]) {
+        direction.y -= 1.;
+    }
+    if keys.any_pressed([KeyCode::ArrowLeft, KeyCode::KeyA]) {
+        direction.x -= 1.;
+    }
+    if keys.any_pressed([KeyCode::ArrowRight, KeyCode::KeyD]) {
+        direction.x += 1.;
+    }
+
+    let pan_speed = 12.;
+    let delta = direction.normalize_or_zero() * pan_speed * time.delta_secs();
+    let limit = Vec2::splat(MAP_SIZE as f32 / 2.);
 
-    if timer.just_finished() {
-        state.set(RollbackState::InRound);
+    for mut transform in &mut cameras {
+        let pos = (transform.translation.xy() + delta).clamp(-limit, limit);
+        transform.translation.x = pos.x;
+        transform.translation.y = pos.y;
     }
 }
 
-fn update_score_ui(mut contexts: EguiContexts, scores: Res<Scores>) -> Result {
-    let Scores(p1_score, p2_score) = *scores;
+fn update_score_ui(
+    mut contexts: EguiContexts,
+    scores: Res<Scores>,
+    num_players: Res<NumPlayers>,
+) -> Result {
+    let scores = scores.0[..**num_players]
+        .iter()
+        .map(|score| score.to_string())
+        .collect::<Vec<_>>()
+        .join(" - ");
 
     egui::Area::new("score".into())
         .anchor(Align2::CENTER_TOP, (0., 25.))
         .show(contexts.ctx_mut()?, |ui| {
             ui.label(
-                RichText::new(format!("{p1_score} - {p2_score}"))
+                RichText::new(scores)
                     .color(Color32::BLACK)
                     .font(FontId::proportional(72.0)),
             );
@@ -661,13 +1029,143 @@ fn update_score_ui(mut contexts: EguiContexts, scores: Res<Scores>) -> Result {
     Ok(())
 }
 
+/// Shows the weapon of each local player, and how many rounds it has left
+fn update_ammo_ui(
+    mut contexts: EguiContexts,
+    local_players: Res<LocalPlayers>,
+    players: Query<(&Player, &Weapon, &Ammo)>,
+) -> Result {
+    let mut players: Vec<_> = players
+        .iter()
+        .filter(|(player, ..)| local_players.0.contains(&player.handle))
+        .collect();
+    players.sort_by_key(|(player, ..)| player.handle);
+
+    egui::Area::new("ammo".into())
+        .anchor(Align2::LEFT_TOP, (25., 25.))
+        .show(contexts.ctx_mut()?, |ui| {
+            for (player, weapon, ammo) in players {
+                let rounds = if ammo.reloading() {
+                    "reloading".to_string()
+                } else {
+                    format!("{}/{}", ammo.rounds, ammo.magazine_size)
+                };
+                let name = weapon.stats().name;
+                ui.label(
+                    RichText::new(format!("Player {}: {name} {rounds}", player.handle + 1))
+                        .color(Color32::BLACK)
+                        .font(FontId::proportional(32.0)),
+                );
+            }
+        });
+
+    Ok(())
+}
+
+fn update_connection_ui(
+    mut contexts: EguiContexts,
+    mut interrupted_peers: ResMut<InterruptedPeers>,
+    match_over: Option<Res<MatchOver>>,
+    notice: Option<ResMut<Notice>>,
+    mut commands: Commands,
+    time: Res<Time<Real>>,
+) -> Result {
+    for timer in interrupted_peers.0.values_mut() {
+        timer.tick(time.delta());
+    }
+    let notice = notice.and_then(|mut notice| {
+        if notice.timer.tick(time.delta()).is_finished() {
+            commands.remove_resource::<Notice>();
+            None
+        } else {
+            Some(notice.message.clone())
+        }
+    });
+
+    let message = if let Some(match_over) = &match_over {
+        match_over.message.clone()
+    } else if let Some(timer) = interrupted_peers.0.values().next() {
+        let seconds_left = timer.remaining_secs().ceil();
+        format!("Connection interrupted ({seconds_left}s)")
+    } else if let Some(notice) = notice {
+        notice
+    } else {
+        return Ok(());
+    };
+
+    egui::Area::new("connection".into())
+        .anchor(Align2::CENTER_CENTER, (0., 0.))
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.label(
+                RichText::new(message)
+                    .color(Color32::BLACK)
+                    .font(FontId::proportional(48.0)),
+            );
+        });
+
+    Ok(())
+}
+
+fn update_spectator_ui(mut contexts: EguiContexts) -> Result {
+    egui::Area::new("spectating".into())
+        .anchor(Align2::LEFT_TOP, (25., 25.))
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.label(
+                RichText::new("Spectating")
+                    .color(Color32::BLACK)
+                    .font(FontId::proportional(32.0)),
+            );
+        });
+
+    Ok(())
+}
+
+fn update_stats_ui(
+    mut contexts: EguiContexts,
+    stats: Res<MatchStats>,
+    num_players: Res<NumPlayers>,
+) -> Result {
+    egui::Window::new("Stats")
+        .anchor(Align2::CENTER_CENTER, (0., 0.))
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            egui::Grid::new("stats_grid").striped(true).show(ui, |ui| {
+                for header in ["Player", "Kills", "Deaths", "Self kills", "Shots"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+
+                for (handle, player_stats) in stats.0[..**num_players].iter().enumerate() {
+                    ui.label(format!("Player {}", handle + 1));
+                    ui.label(player_stats.kills.to_string());
+                    ui.label(player_stats.deaths.to_string());
+                    ui.label(player_stats.self_kills.to_string());
+                    ui.label(player_stats.shots_fired.to_string());
+                    ui.end_row();
+                }
+            });
+        });
+
+    Ok(())
+}
+
 fn update_player_sprites(
-    mut players: Query<(&mut Sprite, &MoveDir, &DistanceTraveled), With<Player>>,
+    mut players: Query<(&mut Sprite, &AimDir, &DistanceTraveled, &Health), With<Player>>,
 ) {
-    for (mut sprite, move_dir, distance) in &mut players {
+    for (mut sprite, aim_dir, distance, health) in &mut players {
+        // fade players out while they can't be hit
+        let alpha = if health.invulnerable_frames > 0 {
+            0.5
+        } else {
+            1.
+        };
+        sprite.color.set_alpha(alpha);
+
         if let Some(atlas) = sprite.texture_atlas.as_mut() {
-            // 8 directional animations, each 45 degrees apart
-            let octant = move_dir.octant();
+            // 8 directional animations, each 45 degrees apart, facing where
+            // the player aims
+            let octant = aim_dir.octant();
 
             // each row has 6 frames, so we multiply the octant index by 6
             // to get the index of the first frame in that row in the texture atlas.
@@ -686,8 +1184,8 @@ fn update_player_sprites(
                 _ => unreachable!(),
             };
 
-            let anim_speed = 4.0; // frames per units of distance traveled
-            let current_frame = (distance.0 * anim_speed) as usize % anim_len;
+            let anim_speed = 4; // frames per map cell traveled
+            let current_frame = (distance.0 * anim_speed / UNIT) as usize % anim_len;
 
             atlas.index = anim_start + current_frame;
         }
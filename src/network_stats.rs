@@ -0,0 +1,204 @@
+use crate::{Config, gameplay::FrameCount};
+use bevy::prelude::*;
+use bevy_egui::{
+    EguiContexts,
+    egui::{self, Align2, Color32, Stroke},
+};
+use bevy_ggrs::Session;
+use std::collections::{BTreeMap, VecDeque};
+
+/// How many samples the graphs show, one per second
+const HISTORY_LEN: usize = 60;
+const GRAPH_SIZE: egui::Vec2 = egui::vec2(240., 32.);
+
+#[derive(Clone, Copy, Debug, Default)]
+struct PeerSample {
+    ping: u128,
+    kbps_sent: usize,
+    local_frames_behind: i32,
+    remote_frames_behind: i32,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct RollbackSample {
+    rollbacks: u32,
+    frames: u32,
+}
+
+/// Connection quality over the last minute
+#[derive(Resource, Debug)]
+pub struct NetworkStatsHistory {
+    /// Samples for each remote player handle
+    peers: BTreeMap<usize, VecDeque<PeerSample>>,
+    rollbacks: VecDeque<RollbackSample>,
+    /// Rollbacks since the last sample
+    current: RollbackSample,
+    last_frame: i32,
+    highest_frame: i32,
+    timer: Timer,
+}
+
+impl Default for NetworkStatsHistory {
+    fn default() -> Self {
+        Self {
+            peers: default(),
+            rollbacks: default(),
+            current: default(),
+            last_frame: 0,
+            highest_frame: 0,
+            timer: Timer::from_seconds(1., TimerMode::Repeating),
+        }
+    }
+}
+
+fn push_sample<T>(samples: &mut VecDeque<T>, sample: T) {
+    samples.push_back(sample);
+    while samples.len() > HISTORY_LEN {
+        samples.pop_front();
+    }
+}
+
+/// Frames are normally simulated one after the other, so ending up on a frame
+/// we've already simulated means we rolled back
+pub fn count_rollbacks(frame: Res<FrameCount>, mut history: ResMut<NetworkStatsHistory>) {
+    let frame = frame.0;
+    if frame <= history.last_frame {
+        history.current.rollbacks += 1;
+    }
+    if frame <= history.highest_frame {
+        history.current.frames += 1;
+    }
+    history.last_frame = frame;
+    history.highest_frame = history.highest_frame.max(frame);
+}
+
+pub fn sample_network_stats(
+    mut history: ResMut<NetworkStatsHistory>,
+    session: Res<Session<Config>>,
+    time: Res<Time<Real>>,
+) {
+    if !history.timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let history = history.as_mut();
+    push_sample(&mut history.rollbacks, std::mem::take(&mut history.current));
+
+    let Session::P2P(session) = session.as_ref() else {
+        return;
+    };
+
+    for handle in session.remote_player_handles() {
+        // GGRS needs a little while after connecting before it has stats
+        let Ok(stats) = session.network_stats(handle) else {
+            continue;
+        };
+        let sample = PeerSample {
+            ping: stats.ping,
+            kbps_sent: stats.kbps_sent,
+            local_frames_behind: stats.local_frames_behind,
+            remote_frames_behind: stats.remote_frames_behind,
+        };
+        push_sample(history.peers.entry(handle).or_default(), sample);
+    }
+}
+
+pub fn network_stats_ui(
+    mut contexts: EguiContexts,
+    mut open: Local<bool>,
+    keys: Res<ButtonInput<KeyCode>>,
+    history: Res<NetworkStatsHistory>,
+) -> Result {
+    if keys.just_pressed(KeyCode::F3) {
+        *open = !*open;
+    }
+    if !*open {
+        return Ok(());
+    }
+
+    egui::Window::new("Network (F3)")
+        .anchor(Align2::LEFT_BOTTOM, (25., -25.))
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            for (handle, samples) in &history.peers {
+                let Some(latest) = samples.back() else {
+                    continue;
+                };
+                ui.strong(format!("Player {}", handle + 1));
+                graph(
+                    ui,
+                    format!("Ping: {} ms", latest.ping),
+                    samples.iter().map(|sample| sample.ping as f32),
+                );
+                graph(
+                    ui,
+                    format!("Sent: {} kbps", latest.kbps_sent),
+                    samples.iter().map(|sample| sample.kbps_sent as f32),
+                );
+                graph(
+                    ui,
+                    format!("Local frames behind: {}", latest.local_frames_behind),
+                    samples
+                        .iter()
+                        .map(|sample| sample.local_frames_behind as f32),
+                );
+                graph(
+                    ui,
+                    format!("Remote frames behind: {}", latest.remote_frames_behind),
+                    samples
+                        .iter()
+                        .map(|sample| sample.remote_frames_behind as f32),
+                );
+                ui.separator();
+            }
+
+            let latest = history.rollbacks.back().copied().unwrap_or_default();
+            graph(
+                ui,
+                format!("Rollbacks: {}/s", latest.rollbacks),
+                history
+                    .rollbacks
+                    .iter()
+                    .map(|sample| sample.rollbacks as f32),
+            );
+            graph(
+                ui,
+                format!("Rolled back frames: {}/s", latest.frames),
+                history.rollbacks.iter().map(|sample| sample.frames as f32),
+            );
+        });
+
+    Ok(())
+}
+
+/// A small line graph, scaled to fit the values. The newest value is on the right.
+fn graph(ui: &mut egui::Ui, label: String, values: impl DoubleEndedIterator<Item = f32>) {
+    ui.label(label);
+
+    let (rect, _) = ui.allocate_exact_size(GRAPH_SIZE, egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0., Color32::from_black_alpha(60));
+
+    let values: Vec<f32> = values.rev().collect();
+    // frames behind can be negative, everything else starts at zero
+    let min = values.iter().copied().fold(0., f32::min);
+    let max = values.iter().copied().fold(0., f32::max);
+    let range = (max - min).max(1.);
+    let step = rect.width() / (HISTORY_LEN - 1) as f32;
+
+    let points = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            egui::pos2(
+                rect.right() - i as f32 * step,
+                rect.bottom() - (value - min) / range * rect.height(),
+            )
+        })
+        .collect();
+    painter.add(egui::Shape::line(
+        points,
+        Stroke::new(1.5, Color32::LIGHT_GREEN),
+    ));
+}
@@ -0,0 +1,357 @@
+use crate::{
+    Config, GameState,
+    args::{Args, InputSource},
+    gameplay::{FrameCount, MAX_PLAYERS, MatchSettings, NumPlayers, SessionSeed},
+    input::PlayerInput,
+    storage::save_file,
+};
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_egui::{
+    EguiContexts,
+    egui::{self, Align2},
+};
+use bevy_ggrs::{LocalInputs, LocalPlayers, PlayerInputs, Session, ggrs};
+
+const MAGIC: &[u8; 4] = b"EXBR";
+/// Bumped whenever the simulation changes, since older replays would play out
+/// differently now. Version 5 is the first with fixed-point movement, swept
+/// bullets and input bits in the order of `Action::ALL`, version 6 records the
+/// tick rate.
+const VERSION: u8 = 6;
+const HEADER_LEN: usize = 18;
+
+/// Simulation speed while seeking forward
+const FAST_FORWARD_SPEED: f32 = 16.;
+
+/// Everything needed to deterministically re-simulate a match
+#[derive(Resource, Debug, Clone)]
+pub struct Replay {
+    pub seed: u64,
+    pub num_players: usize,
+    pub settings: MatchSettings,
+    /// Confirmed inputs of every player, for every frame
+    pub frames: Vec<Vec<PlayerInput>>,
+}
+
+impl Replay {
+    fn load(path: &str) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+        Self::decode(&bytes).map_err(|err| format!("invalid replay {path}: {err}"))
+    }
+
+    /// Inputs rarely change from one frame to the next, so frames are stored
+    /// as runs of identical inputs: `count: u16` followed by `buttons: u8`,
+    /// `aim_x: i8` and `aim_y: i8` for each player
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        bytes.extend(self.seed.to_le_bytes());
+        bytes.push(self.num_players as u8);
+        bytes.push(self.settings.self_damage as u8);
+        bytes.push(self.settings.max_health as u8);
+        bytes.push(self.settings.ricochet as u8);
+        bytes.push(self.settings.tick_rate as u8);
+
+        for run in self.frames.chunk_by(|a, b| a == b) {
+            for chunk in run.chunks(u16::MAX as usize) {
+                bytes.extend((chunk.len() as u16).to_le_bytes());
+                for input in &chunk[0] {
+                    bytes.extend(input.to_bytes());
+                }
+            }
+        }
+
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < MAGIC.len() + 1 || &bytes[..4] != MAGIC {
+            return Err("not a replay file".to_string());
+        }
+        match bytes[4] {
+            VERSION => {}
+            version if version < VERSION => {
+                return Err(format!(
+                    "recorded with an older version of the game (replay version {version}, \
+                     expected {VERSION}), which plays out matches differently"
+                ));
+            }
+            version => return Err(format!("unsupported replay version {version}")),
+        }
+        if bytes.len() < HEADER_LEN {
+            return Err("truncated replay".to_string());
+        }
+
+        let seed = u64::from_le_bytes(bytes[5..13].try_into().unwrap());
+        let num_players = bytes[13] as usize;
+        let self_damage = bytes[14] != 0;
+        let max_health = bytes[15] as u32;
+        let ricochet = bytes[16] != 0;
+        let tick_rate = bytes[17] as u32;
+        if !(2..=MAX_PLAYERS).contains(&num_players) {
+            return Err(format!("invalid number of players {num_players}"));
+        }
+        if max_health == 0 {
+            return Err("players start without health".to_string());
+        }
+        if tick_rate == 0 {
+            return Err("invalid tick rate 0".to_string());
+        }
+
+        let mut frames = Vec::new();
+        for run in bytes[HEADER_LEN..].chunks(2 + num_players * PlayerInput::BYTES) {
+            let [count_lo, count_hi, inputs @ ..] = run else {
+                return Err("truncated replay".to_string());
+            };
+            if inputs.len() != num_players * PlayerInput::BYTES {
+                return Err("truncated replay".to_string());
+            }
+            let count = u16::from_le_bytes([*count_lo, *count_hi]);
+            let inputs = inputs
+                .chunks(PlayerInput::BYTES)
+                .map(|input| PlayerInput::from_bytes(input.try_into().unwrap()))
+                .collect::<Vec<_>>();
+            frames.extend(std::iter::repeat_n(inputs, count as usize));
+        }
+
+        Ok(Self {
+            seed,
+            num_players,
+            settings: MatchSettings {
+                self_damage,
+                max_health,
+                ricochet,
+                tick_rate,
+            },
+            frames,
+        })
+    }
+}
+
+pub fn load_replay(mut commands: Commands, args: Res<Args>) {
+    let InputSource::File(path) = args.input_source() else {
+        return;
+    };
+
+    match Replay::load(&path) {
+        Ok(replay) => commands.insert_resource(replay),
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Inputs of the current match, along with whether they're confirmed yet
+#[derive(Resource, Default)]
+pub struct ReplayRecorder(Vec<(Vec<PlayerInput>, bool)>);
+
+impl ReplayRecorder {
+    /// Inputs from `first_frame` on, up to the first frame that still has
+    /// predicted inputs, as those may still change
+    pub fn confirmed_from(&self, first_frame: usize) -> impl Iterator<Item = &Vec<PlayerInput>> {
+        self.0[first_frame.min(self.0.len())..]
+            .iter()
+            .take_while(|(_, confirmed)| *confirmed)
+            .map(|(inputs, _)| inputs)
+    }
+}
+
+#[derive(Resource, Debug)]
+pub struct ReplayControls {
+    pub paused: bool,
+    pub speed: f32,
+    /// Frame the seek slider is being dragged to
+    dragged_frame: Option<i32>,
+}
+
+impl Default for ReplayControls {
+    fn default() -> Self {
+        Self {
+            paused: false,
+            speed: 1.,
+            dragged_frame: None,
+        }
+    }
+}
+
+/// Frame we're currently seeking to
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct ReplaySeek(i32);
+
+pub fn record_inputs(
+    frame: Res<FrameCount>,
+    inputs: Res<PlayerInputs<Config>>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let confirmed = inputs.iter().all(|(_, status)| {
+        matches!(
+            status,
+            ggrs::InputStatus::Confirmed | ggrs::InputStatus::Disconnected
+        )
+    });
+    let inputs = inputs.iter().map(|(input, _)| *input).collect();
+
+    // frames are re-simulated on rollback, hopefully with confirmed inputs this time
+    recorder.0.truncate(frame.0 as usize);
+    recorder.0.push((inputs, confirmed));
+}
+
+pub fn save_replay(
+    recorder: Res<ReplayRecorder>,
+    seed: Res<SessionSeed>,
+    num_players: Res<NumPlayers>,
+    settings: Res<MatchSettings>,
+    args: Res<Args>,
+) {
+    let Some(path) = &args.record else {
+        return;
+    };
+
+    let frames = recorder.confirmed_from(0).cloned().collect();
+
+    let replay = Replay {
+        seed: **seed,
+        num_players: **num_players,
+        settings: *settings,
+        frames,
+    };
+
+    match save_file(path, &replay.encode()) {
+        Ok(()) => info!("saved replay of {} frames to {path}", replay.frames.len()),
+        Err(err) => error!("failed to save replay {path}: {err}"),
+    }
+}
+
+pub fn start_replay_session(
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<GameState>>,
+    replay: Res<Replay>,
+) {
+    info!("Starting replay of {} frames", replay.frames.len());
+
+    // a synctest session with check distance 0 never rolls back, it just
+    // advances with the inputs we give it
+    let mut session_builder = ggrs::SessionBuilder::<Config>::new()
+        .with_num_players(replay.num_players)
+        .with_check_distance(0);
+
+    for handle in 0..replay.num_players {
+        session_builder = session_builder
+            .add_player(ggrs::PlayerType::Local, handle)
+            .expect("failed to add player");
+    }
+
+    let ggrs_session = session_builder
+        .start_synctest_session()
+        .expect("failed to start session");
+
+    commands.insert_resource(Session::SyncTest(ggrs_session));
+    commands.insert_resource(SessionSeed(replay.seed));
+    commands.insert_resource(NumPlayers(replay.num_players));
+    commands.insert_resource(replay.settings);
+    next_state.set(GameState::InGame);
+}
+
+pub fn replay_inputs(
+    mut commands: Commands,
+    replay: Res<Replay>,
+    frame: Res<FrameCount>,
+    local_players: Res<LocalPlayers>,
+) {
+    let inputs = replay.frames.get(frame.0 as usize);
+
+    let local_inputs: HashMap<_, _> = local_players
+        .0
+        .iter()
+        .map(|handle| {
+            let input = inputs.map_or_else(default, |inputs| inputs[*handle]);
+            (*handle, input)
+        })
+        .collect();
+
+    commands.insert_resource(LocalInputs::<Config>(local_inputs));
+}
+
+/// Drives the rollback schedule through virtual time, so pausing and changing
+/// speed doesn't affect the simulation itself
+pub fn apply_replay_controls(
+    mut commands: Commands,
+    mut time: ResMut<Time<Virtual>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    controls: Res<ReplayControls>,
+    seek: Option<Res<ReplaySeek>>,
+    frame: Res<FrameCount>,
+    replay: Res<Replay>,
+) {
+    if let Some(seek) = seek {
+        if seek.0 < frame.0 {
+            // we can't simulate backwards, so start over and fast forward
+            next_state.set(GameState::Matchmaking);
+            return;
+        }
+
+        if seek.0 > frame.0 {
+            time.unpause();
+            time.set_relative_speed(FAST_FORWARD_SPEED);
+            return;
+        }
+
+        commands.remove_resource::<ReplaySeek>();
+    }
+
+    let at_end = frame.0 as usize >= replay.frames.len();
+    if controls.paused || at_end {
+        time.pause();
+    } else {
+        time.unpause();
+    }
+    time.set_relative_speed(controls.speed);
+}
+
+pub fn replay_ui(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut controls: ResMut<ReplayControls>,
+    keys: Res<ButtonInput<KeyCode>>,
+    frame: Res<FrameCount>,
+    replay: Res<Replay>,
+) -> Result {
+    if keys.just_pressed(KeyCode::Space) {
+        controls.paused = !controls.paused;
+    }
+
+    egui::Window::new("Replay")
+        .anchor(Align2::CENTER_BOTTOM, (0., -25.))
+        .collapsible(false)
+        .resizable(false)
+        .show(contexts.ctx_mut()?, |ui| {
+            ui.horizontal(|ui| {
+                let label = if controls.paused { "Play" } else { "Pause" };
+                if ui.button(label).clicked() {
+                    controls.paused = !controls.paused;
+                }
+
+                for speed in [0.25, 0.5, 1., 2., 4.] {
+                    let selected = controls.speed == speed;
+                    if ui.selectable_label(selected, format!("{speed}x")).clicked() {
+                        controls.speed = speed;
+                    }
+                }
+            });
+
+            let last_frame = replay.frames.len() as i32;
+            let mut slider_frame = controls.dragged_frame.unwrap_or(frame.0);
+            let response =
+                ui.add(egui::Slider::new(&mut slider_frame, 0..=last_frame).text("frame"));
+
+            if response.dragged() {
+                controls.dragged_frame = Some(slider_frame);
+            } else if response.drag_stopped() || response.changed() {
+                controls.dragged_frame = None;
+                commands.insert_resource(ReplaySeek(slider_frame));
+            }
+        });
+
+    Ok(())
+}
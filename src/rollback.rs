@@ -0,0 +1,295 @@
+use crate::{
+    components::*,
+    gameplay::{FrameCount, MatchStats, RollbackState, RoundEndTimer, Scores},
+    weapons::Weapon,
+};
+use bevy::{ecs::component::Mutable, prelude::*};
+use bevy_ggrs::{checksum_hasher, prelude::RollbackApp};
+use std::hash::Hasher;
+
+/// Rollback registration that always comes with a checksum, so desyncs in
+/// gameplay state are caught as soon as they happen, not when they eventually
+/// show up in positions.
+pub trait ChecksummedRollbackApp {
+    fn checksummed_component_with_copy<T>(&mut self, checksum: fn(&T) -> u64) -> &mut Self
+    where
+        T: Component<Mutability = Mutable> + Copy;
+
+    fn checksummed_resource_with_copy<T>(&mut self, checksum: fn(&T) -> u64) -> &mut Self
+    where
+        T: Resource + Copy;
+
+    /// Registers all the game's rollback state. This is the only place
+    /// rollback should be registered, so it's easy to see what's checksummed.
+    fn register_rollback_state(&mut self) -> &mut Self;
+}
+
+impl ChecksummedRollbackApp for App {
+    fn checksummed_component_with_copy<T>(&mut self, checksum: fn(&T) -> u64) -> &mut Self
+    where
+        T: Component<Mutability = Mutable> + Copy,
+    {
+        self.rollback_component_with_copy::<T>()
+            .checksum_component::<T>(checksum)
+    }
+
+    fn checksummed_resource_with_copy<T>(&mut self, checksum: fn(&T) -> u64) -> &mut Self
+    where
+        T: Resource + Copy,
+    {
+        self.rollback_resource_with_copy::<T>()
+            .checksum_resource::<T>(checksum)
+    }
+
+    fn register_rollback_state(&mut self) -> &mut Self {
+        self.checksummed_resource_with_copy::<RoundEndTimer>(checksum_round_end_timer)
+            .checksummed_resource_with_copy::<Scores>(checksum_scores)
+            .checksummed_resource_with_copy::<MatchStats>(checksum_match_stats)
+            .checksummed_resource_with_copy::<FrameCount>(checksum_frame_count)
+            .checksummed_component_with_copy::<Position>(checksum_position)
+            .checksummed_component_with_copy::<Bullet>(checksum_bullet)
+            .checksummed_component_with_copy::<Ammo>(checksum_ammo)
+            .checksummed_component_with_copy::<Weapon>(checksum_weapon)
+            .checksummed_component_with_copy::<WeaponPickup>(checksum_weapon_pickup)
+            .checksummed_component_with_copy::<Player>(checksum_player)
+            .checksummed_component_with_copy::<Health>(checksum_health)
+            .checksummed_component_with_copy::<Wall>(checksum_wall)
+            .checksummed_component_with_copy::<MoveDir>(checksum_move_dir)
+            .checksummed_component_with_copy::<AimDir>(checksum_aim_dir)
+            .checksummed_component_with_copy::<DistanceTraveled>(checksum_distance_traveled)
+            .checksummed_component_with_copy::<PreviousPosition>(checksum_previous_position)
+            // the state itself is registered for rollback by `init_ggrs_state`
+            .checksum_resource::<State<RollbackState>>(checksum_rollback_state)
+    }
+}
+
+/// Feeds values into a checksum with a fixed width. `derive(Hash)` would hash
+/// `usize`s, enum discriminants and slice lengths as 4 bytes on wasm and 8 on
+/// native builds, so peers on different platforms would never agree.
+pub struct Checksum<H>(H);
+
+/// Starts a checksum with the hasher GGRS uses for its own checksums
+pub fn checksum() -> Checksum<impl Hasher> {
+    Checksum(checksum_hasher())
+}
+
+impl<H: Hasher> Checksum<H> {
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.0.write(bytes);
+        self
+    }
+
+    pub fn u8(self, value: u8) -> Self {
+        self.bytes(&[value])
+    }
+
+    pub fn bool(self, value: bool) -> Self {
+        self.u8(value as u8)
+    }
+
+    pub fn u32(self, value: u32) -> Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn i32(self, value: i32) -> Self {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// Player handles are `usize`s, but never more than `MAX_PLAYERS`
+    pub fn handle(self, handle: usize) -> Self {
+        self.u32(handle as u32)
+    }
+
+    pub fn ivec2(self, value: IVec2) -> Self {
+        self.i32(value.x).i32(value.y)
+    }
+
+    pub fn finish(self) -> u64 {
+        self.0.finish()
+    }
+}
+
+fn checksum_round_end_timer(timer: &RoundEndTimer) -> u64 {
+    checksum().u32(timer.0).finish()
+}
+
+fn checksum_scores(scores: &Scores) -> u64 {
+    scores
+        .0
+        .iter()
+        .fold(checksum(), |checksum, score| checksum.u32(*score))
+        .finish()
+}
+
+fn checksum_match_stats(stats: &MatchStats) -> u64 {
+    stats
+        .0
+        .iter()
+        .fold(checksum(), |checksum, stats| {
+            checksum
+                .u32(stats.kills)
+                .u32(stats.deaths)
+                .u32(stats.self_kills)
+                .u32(stats.shots_fired)
+        })
+        .finish()
+}
+
+fn checksum_frame_count(frame: &FrameCount) -> u64 {
+    checksum().i32(frame.0).finish()
+}
+
+fn checksum_position(position: &Position) -> u64 {
+    checksum().ivec2(position.0).finish()
+}
+
+fn checksum_bullet(bullet: &Bullet) -> u64 {
+    checksum()
+        .handle(bullet.owner)
+        .u8(bullet.weapon as u8)
+        .u32(bullet.frames_left)
+        .u32(bullet.bounces_left)
+        .finish()
+}
+
+fn checksum_ammo(ammo: &Ammo) -> u64 {
+    checksum()
+        .u32(ammo.rounds)
+        .u32(ammo.magazine_size)
+        .u32(ammo.reload_frames)
+        .u32(ammo.cooldown_frames)
+        .bool(ammo.trigger_released)
+        .finish()
+}
+
+fn checksum_weapon(weapon: &Weapon) -> u64 {
+    checksum().u8(*weapon as u8).finish()
+}
+
+fn checksum_weapon_pickup(pickup: &WeaponPickup) -> u64 {
+    checksum().u8(pickup.weapon as u8).finish()
+}
+
+fn checksum_player(player: &Player) -> u64 {
+    checksum().handle(player.handle).finish()
+}
+
+fn checksum_health(health: &Health) -> u64 {
+    checksum()
+        .u32(health.current)
+        .u32(health.max)
+        .u32(health.invulnerable_frames)
+        .finish()
+}
+
+fn checksum_wall(wall: &Wall) -> u64 {
+    checksum().ivec2(wall.size).finish()
+}
+
+fn checksum_move_dir(direction: &MoveDir) -> u64 {
+    checksum().ivec2(direction.0).finish()
+}
+
+fn checksum_aim_dir(direction: &AimDir) -> u64 {
+    checksum().ivec2(direction.0).finish()
+}
+
+fn checksum_distance_traveled(distance: &DistanceTraveled) -> u64 {
+    checksum().i32(distance.0).finish()
+}
+
+fn checksum_previous_position(position: &PreviousPosition) -> u64 {
+    checksum().ivec2(position.0).finish()
+}
+
+fn checksum_rollback_state(state: &State<RollbackState>) -> u64 {
+    checksum().u8(state.get().clone() as u8).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use bevy::platform::collections::HashSet;
+    use bevy_ggrs::{ChecksumFlag, GgrsPlugin, GgrsSnapshots, SaveWorld};
+    use std::any::type_name;
+
+    /// Name of a generic type up to its type parameters, e.g. `a::B<`
+    fn generic_name<T>() -> &'static str {
+        let name = type_name::<T>();
+        &name[..=name.find('<').unwrap()]
+    }
+
+    /// First type parameter in a type name, e.g. `c::D` in `a::B<c::D, e::F>`
+    fn first_type_param(name: &str) -> Option<&str> {
+        let start = name.find('<')? + 1;
+        let mut depth = 0;
+        for (i, c) in name[start..].char_indices() {
+            match c {
+                '<' => depth += 1,
+                '>' | ',' if depth == 0 => return Some(&name[start..start + i]),
+                '>' => depth -= 1,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn rollback_state_is_checksummed() {
+        let mut app = App::new();
+        app.add_plugins(GgrsPlugin::<Config>::default())
+            .register_rollback_state();
+
+        // the components flagging each type's checksum are registered when
+        // the checksum systems are initialized
+        app.world_mut()
+            .schedule_scope(SaveWorld, |world, schedule| {
+                schedule.initialize(world).unwrap();
+            });
+
+        let names: Vec<String> = app
+            .world()
+            .components()
+            .iter_registered()
+            .map(|info| info.name().to_string())
+            .collect();
+        let own_types_in = |generic: &str| -> HashSet<&str> {
+            names
+                .iter()
+                .filter(|name| name.starts_with(generic))
+                .filter_map(|name| first_type_param(name))
+                .filter(|param| param.starts_with(concat!(env!("CARGO_CRATE_NAME"), "::")))
+                .collect()
+        };
+
+        let rolled_back = own_types_in(generic_name::<GgrsSnapshots<(), ()>>());
+        let checksummed = own_types_in(generic_name::<ChecksumFlag<()>>());
+        assert!(!rolled_back.is_empty());
+
+        let mut unchecked: Vec<_> = rolled_back.difference(&checksummed).collect();
+        unchecked.sort();
+        assert!(
+            unchecked.is_empty(),
+            "rolled back, but not checksummed: {unchecked:?}"
+        );
+    }
+
+    /// Peers only agree on checksums if they don't depend on the platform, so
+    /// these must never change, no matter where the tests run
+    #[test]
+    fn checksums_are_pinned() {
+        let bullet = Bullet {
+            owner: 3,
+            weapon: Weapon::Rifle,
+            frames_left: 40,
+            bounces_left: 2,
+        };
+        assert_eq!(checksum_bullet(&bullet), 0x74DFA7A18CFBC677);
+        assert_eq!(checksum_player(&Player { handle: 7 }), 0x6B6B38FFA3823F04);
+        assert_eq!(
+            checksum_position(&Position(IVec2::new(-1024, 20480))),
+            0x1C28C72852E25759
+        );
+    }
+}
@@ -0,0 +1,63 @@
+/// Saves a file to disk on native, or as a browser download on wasm
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_file(file_name: &str, contents: &[u8]) -> Result<(), String> {
+    std::fs::write(file_name, contents).map_err(|err| err.to_string())
+}
+
+/// Saves a file to disk on native, or as a browser download on wasm
+#[cfg(target_arch = "wasm32")]
+pub fn save_file(file_name: &str, contents: &[u8]) -> Result<(), String> {
+    use wasm_bindgen::JsCast;
+
+    let js_err = |err: wasm_bindgen::JsValue| format!("{err:?}");
+
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(contents));
+    let blob = web_sys::Blob::new_with_u8_array_sequence(&parts).map_err(js_err)?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob).map_err(js_err)?;
+
+    let document = web_sys::window()
+        .and_then(|window| window.document())
+        .ok_or("no document")?;
+    let anchor: web_sys::HtmlAnchorElement = document
+        .create_element("a")
+        .map_err(js_err)?
+        .dyn_into()
+        .map_err(|_| "not an anchor element")?;
+    anchor.set_href(&url);
+    anchor.set_download(file_name);
+    anchor.click();
+
+    web_sys::Url::revoke_object_url(&url).map_err(js_err)
+}
+
+/// Reads a config file on native, or a localStorage entry on wasm
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load_config(name: &str) -> Option<String> {
+    std::fs::read_to_string(name).ok()
+}
+
+/// Reads a config file on native, or a localStorage entry on wasm
+#[cfg(target_arch = "wasm32")]
+pub fn load_config(name: &str) -> Option<String> {
+    local_storage()?.get_item(name).ok()?
+}
+
+/// Writes a config file on native, or a localStorage entry on wasm
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save_config(name: &str, contents: &str) -> Result<(), String> {
+    std::fs::write(name, contents).map_err(|err| err.to_string())
+}
+
+/// Writes a config file on native, or a localStorage entry on wasm
+#[cfg(target_arch = "wasm32")]
+pub fn save_config(name: &str, contents: &str) -> Result<(), String> {
+    local_storage()
+        .ok_or("no local storage")?
+        .set_item(name, contents)
+        .map_err(|err| format!("{err:?}"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok()?
+}
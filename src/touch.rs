@@ -0,0 +1,99 @@
+use crate::input::{Action, quantize_stick};
+use bevy::{input::touch::Touch, prelude::*, window::PrimaryWindow};
+use bevy_egui::{
+    EguiContexts,
+    egui::{self, Color32, FontId, Stroke},
+};
+
+/// How far a touch has to move from where it started to tilt the stick fully
+const STICK_RADIUS: f32 = 60.;
+const FIRE_BUTTON_RADIUS: f32 = 50.;
+/// Distance from the bottom corners where the controls are drawn when idle
+const CONTROLS_INSET: f32 = 100.;
+
+/// Touches that start on the left half of the screen are a virtual stick,
+/// centered where the touch started. Touches on the right half fire.
+fn is_stick_touch(touch: &Touch, window: &Window) -> bool {
+    touch.start_position().x < window.width() / 2.
+}
+
+/// Screen space offset of the stick from its center, in stick radii
+fn stick_offset(touch: &Touch) -> Vec2 {
+    let offset = (touch.position() - touch.start_position()) / STICK_RADIUS;
+    offset.clamp_length_max(1.)
+}
+
+/// Turns the virtual stick and fire button into the same input bits as a gamepad
+pub fn touch_input(touches: &Touches, window: &Window) -> u8 {
+    let mut input = 0;
+
+    if let Some(touch) = touches.iter().find(|touch| is_stick_touch(touch, window)) {
+        // screen coordinates grow downwards
+        let offset = stick_offset(touch);
+        input |= quantize_stick(Vec2::new(offset.x, -offset.y));
+    }
+
+    if touches.iter().any(|touch| !is_stick_touch(touch, window)) {
+        input |= Action::Fire.bit();
+    }
+
+    input
+}
+
+/// Draws the virtual stick and fire button, once the player has touched the screen
+pub fn touch_controls_ui(
+    mut contexts: EguiContexts,
+    mut touch_detected: Local<bool>,
+    touches: Res<Touches>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+) -> Result {
+    *touch_detected |= touches.any_just_pressed();
+    if !*touch_detected {
+        return Ok(());
+    }
+
+    let window = windows.single()?;
+    let ctx = contexts.ctx_mut()?;
+    let painter = ctx.layer_painter(egui::LayerId::new(
+        egui::Order::Foreground,
+        egui::Id::new("touch_controls"),
+    ));
+    let pos = |v: Vec2| egui::pos2(v.x, v.y);
+    let idle = Color32::from_black_alpha(40);
+    let active = Color32::from_black_alpha(100);
+
+    let stick_touch = touches.iter().find(|touch| is_stick_touch(touch, window));
+    let (stick_center, knob_offset) = match stick_touch {
+        Some(touch) => (touch.start_position(), stick_offset(touch) * STICK_RADIUS),
+        None => (
+            Vec2::new(CONTROLS_INSET, window.height() - CONTROLS_INSET),
+            Vec2::ZERO,
+        ),
+    };
+    painter.circle_stroke(pos(stick_center), STICK_RADIUS, Stroke::new(3., idle));
+    painter.circle_filled(
+        pos(stick_center + knob_offset),
+        STICK_RADIUS / 2.,
+        if stick_touch.is_some() { active } else { idle },
+    );
+
+    let fire_center = Vec2::new(
+        window.width() - CONTROLS_INSET,
+        window.height() - CONTROLS_INSET,
+    );
+    let firing = touches.iter().any(|touch| !is_stick_touch(touch, window));
+    painter.circle_filled(
+        pos(fire_center),
+        FIRE_BUTTON_RADIUS,
+        if firing { active } else { idle },
+    );
+    painter.text(
+        pos(fire_center),
+        egui::Align2::CENTER_CENTER,
+        "FIRE",
+        FontId::proportional(20.),
+        Color32::WHITE,
+    );
+
+    Ok(())
+}
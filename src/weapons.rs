@@ -0,0 +1,129 @@
+use crate::fixed::{UNIT, fixed, normalize};
+use bevy::prelude::*;
+
+/// The gun a player is holding, and the gun a bullet was fired from
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Weapon {
+    /// What every player starts the round with
+    #[default]
+    Pistol,
+    Shotgun,
+    Rifle,
+    Rocket,
+}
+
+/// Everything that makes a weapon different from the others. Frames are
+/// counted at `DEFAULT_TICK_RATE`, see `MatchSettings::frames`
+#[derive(Debug)]
+pub struct WeaponStats {
+    pub name: &'static str,
+    /// `UNIT`s per second
+    pub bullet_speed: i32,
+    /// Damage per bullet, players have `--max-health` health
+    pub damage: u32,
+    /// How far the outermost pellets go sideways for every `UNIT` forward
+    pub spread: i32,
+    /// Bullets fired per shot
+    pub pellets: u32,
+    /// Frames between shots
+    pub cooldown_frames: u32,
+    /// Keeps firing while fire is held, instead of once per press
+    pub automatic: bool,
+    pub magazine_size: u32,
+    pub reload_frames: u32,
+    /// In `UNIT`s
+    pub bullet_size: IVec2,
+    /// Frames until a bullet disappears, if it didn't hit anything
+    pub lifetime_frames: u32,
+    /// Times a bullet bounces off walls with `--ricochet`
+    pub bounces: u32,
+}
+
+const PISTOL: WeaponStats = WeaponStats {
+    name: "Pistol",
+    bullet_speed: fixed(20.),
+    damage: 1,
+    spread: 0,
+    pellets: 1,
+    cooldown_frames: 12,
+    automatic: false,
+    magazine_size: 6,
+    reload_frames: 90,
+    bullet_size: IVec2::new(fixed(0.3), fixed(0.1)),
+    lifetime_frames: 150,
+    bounces: 2,
+};
+
+const SHOTGUN: WeaponStats = WeaponStats {
+    name: "Shotgun",
+    bullet_speed: fixed(18.),
+    damage: 1,
+    spread: fixed(0.31),
+    pellets: 5,
+    cooldown_frames: 40,
+    automatic: false,
+    magazine_size: 2,
+    reload_frames: 120,
+    bullet_size: IVec2::new(fixed(0.15), fixed(0.15)),
+    lifetime_frames: 25,
+    bounces: 1,
+};
+
+const RIFLE: WeaponStats = WeaponStats {
+    name: "Rifle",
+    bullet_speed: fixed(35.),
+    damage: 1,
+    spread: 0,
+    pellets: 1,
+    cooldown_frames: 6,
+    automatic: true,
+    magazine_size: 20,
+    reload_frames: 120,
+    bullet_size: IVec2::new(fixed(0.4), fixed(0.08)),
+    lifetime_frames: 90,
+    bounces: 3,
+};
+
+const ROCKET: WeaponStats = WeaponStats {
+    name: "Rocket",
+    bullet_speed: fixed(8.),
+    damage: 3,
+    spread: 0,
+    pellets: 1,
+    cooldown_frames: 60,
+    automatic: false,
+    magazine_size: 1,
+    reload_frames: 150,
+    bullet_size: IVec2::new(fixed(0.5), fixed(0.25)),
+    lifetime_frames: 360,
+    bounces: 0,
+};
+
+impl Weapon {
+    /// Weapons that can be picked up on the map
+    pub const PICKUPS: [Weapon; 3] = [Weapon::Shotgun, Weapon::Rifle, Weapon::Rocket];
+
+    pub fn stats(self) -> &'static WeaponStats {
+        match self {
+            Weapon::Pistol => &PISTOL,
+            Weapon::Shotgun => &SHOTGUN,
+            Weapon::Rifle => &RIFLE,
+            Weapon::Rocket => &ROCKET,
+        }
+    }
+}
+
+impl WeaponStats {
+    /// Directions of the pellets of a single shot, spread evenly around `aim`
+    pub fn pellet_directions(&self, aim: IVec2) -> impl Iterator<Item = IVec2> + use<> {
+        let pellets = self.pellets as i32;
+        let spread = self.spread;
+        (0..pellets).map(move |i| {
+            if pellets == 1 {
+                return aim;
+            }
+            let sideways = -spread + 2 * spread * i / (pellets - 1);
+            normalize(aim * UNIT + aim.perp() * sideways)
+        })
+    }
+}